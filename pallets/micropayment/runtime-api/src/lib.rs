@@ -0,0 +1,31 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+
+use codec::Codec;
+use sp_std::vec::Vec;
+
+sp_api::decl_runtime_apis! {
+    pub trait MicropaymentApi<AccountId, Balance, PairSummary, ClaimRecord> where
+        AccountId: Codec,
+        Balance: Codec,
+        PairSummary: Codec,
+        ClaimRecord: Codec,
+    {
+        /// Session ids in `start..start + count` already consumed in the channel from `sender`
+        /// to `receiver`. `count` is capped by the pallet's `MAX_SESSION_SCAN`.
+        fn consumed_session_ids(sender: AccountId, receiver: AccountId, start: u32, count: u32) -> Vec<u32>;
+        /// Nonce of the open channel from `sender` to `receiver`, `None` if there is no channel.
+        fn channel_epoch(sender: AccountId, receiver: AccountId) -> Option<u64>;
+        /// Channel, epoch, claimed amount and highest consumed session id of the pair from
+        /// `sender` to `receiver`, `None` if no channel was ever opened between them.
+        fn pair_summary(sender: AccountId, receiver: AccountId) -> Option<PairSummary>;
+        /// Most the receiver can still claim from the channel from `sender`, bounded by the
+        /// deposit and quota; zero if there is no channel or it is exhausted.
+        fn remaining_claimable(sender: AccountId, receiver: AccountId) -> Balance;
+        /// Logged claims of the channel from `sender` to `receiver` with a sequence number of
+        /// `start` or more, oldest first; at most `count`, capped by the pallet's `MaxClaimLog`.
+        fn claim_log(sender: AccountId, receiver: AccountId, start: u32, count: u32) -> Vec<(u32, ClaimRecord)>;
+        /// Whether the channel from `sender` to `receiver` is closing and its challenge period is
+        /// not over yet.
+        fn is_in_challenge_window(sender: AccountId, receiver: AccountId) -> bool;
+    }
+}
@@ -1,37 +1,283 @@
 #![cfg_attr(not(feature = "std"), no_std)]
 
 use frame_support::codec::{Decode, Encode};
-use frame_support::traits::{Currency, ExistenceRequirement::AllowDeath, Time, Vec};
-use frame_support::{decl_event, decl_module, decl_storage, dispatch::DispatchResult, ensure};
+use frame_support::storage::IterableStorageDoubleMap;
+use frame_support::traits::{
+    BalanceStatus, Currency, EnsureOrigin, ExistenceRequirement::AllowDeath, Get,
+    ReservableCurrency, Time, Vec,
+};
+use frame_support::{
+    decl_error, decl_event, decl_module, decl_storage,
+    dispatch::{DispatchResult, DispatchResultWithPostInfo},
+    ensure,
+    weights::Weight,
+};
 use frame_system::{self, ensure_signed};
-use sp_core::sr25519;
-use sp_io::crypto::sr25519_verify;
-use sp_runtime::traits::Saturating;
+use sp_core::{ecdsa, sr25519};
+use sp_io::crypto::{ecdsa_verify, sr25519_verify};
+use sp_runtime::{
+    traits::{Convert, SaturatedConversion, Saturating, Zero},
+    RuntimeDebug,
+};
+
+#[cfg(test)]
+mod mock;
+
+#[cfg(test)]
+mod tests;
 
 /// Configure the pallet by specifying the parameters and types on which it depends.
 pub trait Trait: frame_system::Trait {
     /// Because this pallet emits events, it depends on the runtime's definition of an event.
     type Event: From<Event<Self>> + Into<<Self as frame_system::Trait>::Event>;
-    type Currency: Currency<Self::AccountId>;
+    type Currency: Currency<Self::AccountId> + ReservableCurrency<Self::AccountId>;
     type Timestamp: Time;
+    /// Origin allowed to open channels, resolving to the account that funds the channel.
+    /// Use `EnsureSigned` to only let accounts open channels for themselves.
+    type OpenOrigin: EnsureOrigin<Self::Origin, Success = Self::AccountId>;
+    /// Longest raw signature the claim calls accept; longer ones are rejected before any crypto work.
+    type MaxSignatureLen: Get<u32>;
+    /// Smallest amount a single claim may settle, unless it takes everything left in the channel.
+    /// Keep it at or above the existential deposit, a smaller payout to an empty receiver
+    /// account would be dust.
+    type MinClaimAmount: Get<BalanceOf<Self>>;
+    /// Account owning a compressed secp256k1 public key, used by `claim_payment_with_pubkey`.
+    type AccountFromPubkey: Convert<[u8; 33], Self::AccountId>;
+    /// Most channels `close_all_for_sender` looks at in one call.
+    type MaxCloseBatch: Get<u32>;
+    /// Called at the end of every successful `open_channel`.
+    type OnChannelOpened: OnChannelOpened<Self::AccountId, BalanceOf<Self>>;
+    /// Decides whether two distinct accounts are controlled by the same party, e.g. through a
+    /// proxy, so that a channel between them would be a payment to self.
+    type SameEntityCheck: SameEntity<Self::AccountId>;
+    /// How long the receiver has to claim outstanding vouchers after the sender calls
+    /// `initiate_close`, in milliseconds.
+    type ChallengePeriod: Get<Moment<Self>>;
+    /// Called after every payment a receiver gets out of a channel.
+    type OnClaim: OnClaim<Self::AccountId, BalanceOf<Self>>;
+    /// Origin allowed to repair channel accounting with `reconcile_channel` and to close any
+    /// channel with `force_close_channel`.
+    type ForceOrigin: EnsureOrigin<Self::Origin>;
+    /// How long before its expiration a channel gets a `ChannelExpiringSoon` event, in milliseconds.
+    type WarningWindow: Get<Moment<Self>>;
+    /// Most `ChannelExpiringSoon` events emitted in one block, the rest wait for the next blocks.
+    type MaxExpiryWarnings: Get<u32>;
+    /// Converts voucher amounts into the currency the deposit is held in.
+    type RateProvider: RateProvider<BalanceOf<Self>>;
+    /// How many blocks after the one a channel is opened in claims are refused. Until then the
+    /// sender can take the whole deposit back with `finalize_close`.
+    type ClaimGracePeriod: Get<Self::BlockNumber>;
+    /// Most `ClaimLog` entries kept per channel, older ones are dropped. 0 turns the log off.
+    type MaxClaimLog: Get<u32>;
+    /// Most claims, `.0`, any receiver can make within a window of `.1` blocks, across all its
+    /// channels. A zero window turns the limit off.
+    type GlobalClaimRate: Get<(u32, Self::BlockNumber)>;
+    /// Whether voucher signatures are checked, by every call claiming vouchers. Only turn this off
+    /// on test or permissioned chains where every sender is trusted; session ids, expiry and
+    /// deposits are enforced either way.
+    type RequireSignature: Get<bool>;
+    /// Blocks a deferred `close_channel` waits before `finalize_settlement` moves the funds.
+    type SettlementWindow: Get<Self::BlockNumber>;
+}
+
+/// Exchange rate between the unit vouchers are signed in and the settlement currency.
+pub trait RateProvider<Balance> {
+    /// Amount paid out of the deposit for a voucher worth `amount`.
+    fn to_settlement(amount: Balance) -> Balance;
+}
+
+impl<Balance> RateProvider<Balance> for () {
+    fn to_settlement(amount: Balance) -> Balance {
+        amount
+    }
+}
+
+/// Handler for other pallets that want to react to settled payments.
+pub trait OnClaim<AccountId, Balance> {
+    /// `receiver` was paid `amount` out of its channel from `sender`.
+    fn on_claim(sender: &AccountId, receiver: &AccountId, amount: Balance);
+}
+
+impl<AccountId, Balance> OnClaim<AccountId, Balance> for () {
+    fn on_claim(_: &AccountId, _: &AccountId, _: Balance) {}
+}
+
+/// Runtime knowledge of accounts acting for one another.
+pub trait SameEntity<AccountId> {
+    /// Whether `a` and `b` belong to the same party.
+    fn is_same_entity(a: &AccountId, b: &AccountId) -> bool;
+}
+
+impl<AccountId> SameEntity<AccountId> for () {
+    fn is_same_entity(_: &AccountId, _: &AccountId) -> bool {
+        false
+    }
 }
 
+/// Handler for other pallets that want to react to new channels.
+pub trait OnChannelOpened<AccountId, Balance> {
+    /// A channel from `sender` to `receiver` was opened with `deposit` reserved.
+    fn on_channel_opened(sender: &AccountId, receiver: &AccountId, deposit: Balance);
+}
+
+impl<AccountId, Balance> OnChannelOpened<AccountId, Balance> for () {
+    fn on_channel_opened(_: &AccountId, _: &AccountId, _: Balance) {}
+}
+
+/// Prefix of every signed voucher, so that a voucher signature is not valid for anything else.
+pub const VOUCHER_DOMAIN: &[u8] = b"e2-micropay-v1";
+
+/// Most session ids `consumed_session_ids` looks at in one call.
+pub const MAX_SESSION_SCAN: u32 = 1_000;
+
+/// Length of the expiration ranges channels are grouped in for expiry warnings, in milliseconds.
+pub const EXPIRY_BUCKET: u64 = 60_000;
+
+/// Most expiry buckets `on_initialize` looks at in one block.
+pub const MAX_EXPIRY_BUCKET_SCAN: u64 = 16;
+
+/// Weight charged by `claim_best` for every voucher whose signature it verifies.
+pub const CLAIM_BEST_VOUCHER_WEIGHT: Weight = 10_000;
+
+/// Weight charged by `close_all_for_sender` for every channel it looks at.
+pub const CLOSE_ALL_CHANNEL_WEIGHT: Weight = 10_000;
+
 type BalanceOf<T> =
     <<T as Trait>::Currency as Currency<<T as frame_system::Trait>::AccountId>>::Balance;
 
 type Moment<T> = <<T as Trait>::Timestamp as Time>::Moment;
 
-type ChannelOf<T> = Chan<<T as frame_system::Trait>::AccountId, Moment<T>>;
+type ChannelOf<T> = Chan<
+    <T as frame_system::Trait>::AccountId,
+    Moment<T>,
+    BalanceOf<T>,
+    <T as frame_system::Trait>::BlockNumber,
+>;
+
+type VoucherOf<T> = Voucher<
+    <T as frame_system::Trait>::AccountId,
+    BalanceOf<T>,
+    <T as frame_system::Trait>::BlockNumber,
+>;
+
+// (session_id, amount, valid_until, signature) of a voucher passed to net_settle
+type NetVoucherOf<T> = (
+    u32,
+    BalanceOf<T>,
+    <T as frame_system::Trait>::BlockNumber,
+    TypedSignature,
+);
+
+type StreamOf<T> = Stream<BalanceOf<T>, <T as frame_system::Trait>::BlockNumber>;
+
+// (session_id, amount paid out of the deposit, block) of a claim, no session for stream claims
+pub type ClaimRecordOf<T> = (
+    Option<u32>,
+    BalanceOf<T>,
+    <T as frame_system::Trait>::BlockNumber,
+);
+
+pub type PairSummaryOf<T> = PairSummary<
+    <T as frame_system::Trait>::AccountId,
+    Moment<T>,
+    BalanceOf<T>,
+    <T as frame_system::Trait>::BlockNumber,
+>;
 
 // struct to store the registered Device Informatin
 // TODO: use blockNumber instead of timestamp
-#[derive(Decode, Encode, Default)]
-pub struct Chan<AccountId, Timestamp> {
-    sender: AccountId,
-    receiver: AccountId,
-    nonce: u64,
-    opened: Timestamp,
-    expiration: Timestamp,
+#[derive(Decode, Encode, Default, Clone, PartialEq, RuntimeDebug)]
+pub struct Chan<AccountId, Timestamp, Balance, BlockNumber> {
+    pub sender: AccountId,
+    pub receiver: AccountId,
+    pub nonce: u64,
+    pub opened: Timestamp,
+    // block the channel was opened in, ClaimGracePeriod counts from it
+    pub opened_at: BlockNumber,
+    pub expiration: Timestamp,
+    // amount reserved from the sender when the channel is opened
+    pub deposit: Balance,
+    // total amount the receiver has claimed out of the deposit so far
+    pub claimed: Balance,
+    pub state: ChannelState<Timestamp>,
+    // most the receiver may claim in total, set by the sender; None means up to the deposit
+    pub quota: Option<Balance>,
+}
+
+// where a channel is in its lifecycle
+#[derive(Decode, Encode, Clone, Copy, PartialEq, Eq, RuntimeDebug)]
+pub enum ChannelState<Timestamp> {
+    // claims are accepted and the sender can extend it
+    Open,
+    // the sender asked to close, the receiver can still claim until at, from then on the
+    // sender can finalize the close
+    Closing { at: Timestamp },
+    // the whole deposit has been claimed, all that is left is closing it
+    Settled,
+}
+
+impl<Timestamp> Default for ChannelState<Timestamp> {
+    fn default() -> Self {
+        ChannelState::Open
+    }
+}
+
+// what a sender signs for each micropayment; the signature is on the blake2_256 hash of
+// its SCALE encoding, sender and receiver identify the channel and nonce its generation
+#[derive(Decode, Encode, Clone, PartialEq, RuntimeDebug)]
+pub struct Voucher<AccountId, Balance, BlockNumber> {
+    pub sender: AccountId,
+    pub receiver: AccountId,
+    pub nonce: u64,
+    pub session_id: u32,
+    pub amount: Balance,
+    // last block in which the voucher can be claimed
+    pub valid_until: BlockNumber,
+}
+
+// signature passed to claim_payment; its scheme and length are fixed when the call is decoded,
+// so a signature of the wrong kind is told apart before any crypto runs
+#[derive(Decode, Encode, Clone, PartialEq, Eq, RuntimeDebug)]
+pub enum TypedSignature {
+    Ecdsa(ecdsa::Signature),
+    Sr25519(sr25519::Signature),
+}
+
+// streaming terms of a channel opened with open_stream_channel
+#[derive(Decode, Encode, Default, Clone, PartialEq, RuntimeDebug)]
+pub struct Stream<Balance, BlockNumber> {
+    // amount the receiver earns per block
+    pub rate: Balance,
+    // block up to which the receiver has been paid
+    pub last_claim: BlockNumber,
+    // most a single voucher claim can settle on a hybrid channel, None for a pure stream
+    pub max_per_claim: Option<Balance>,
+}
+
+// everything stored about an account pair, returned by pair_summary in one read
+#[derive(Decode, Encode, Clone, PartialEq, RuntimeDebug)]
+pub struct PairSummary<AccountId, Timestamp, Balance, BlockNumber> {
+    // the open channel, None once it is closed
+    pub channel: Option<Chan<AccountId, Timestamp, Balance, BlockNumber>>,
+    // number of channels ever opened for the pair, i.e. the nonce the next one gets
+    pub epoch: u64,
+    // amount claimed so far from the open channel
+    pub claimed: Balance,
+    // largest consumed session id of the open channel
+    pub highest_session_id: Option<u32>,
+}
+
+// how a channel was closed
+#[derive(Decode, Encode, Clone, Copy, PartialEq, Eq, RuntimeDebug)]
+pub enum CloseKind {
+    // closed by the receiver, who gives up claiming the rest of the deposit
+    Cooperative,
+    // closed by the sender without the receiver's consent
+    Unilateral,
+    // closed by a privileged origin
+    Forced,
+    // closed because the channel ran past its expiration
+    Expired,
 }
 
 // events
@@ -42,19 +288,164 @@ decl_event!(
         Timestamp = Moment<T>,
         Balance = BalanceOf<T>,
     {
-        ChannelOpened(AccountId, AccountId, u64, Timestamp, Timestamp),
-        ChannelClosed(AccountId, AccountId, Timestamp),
-        ClaimPayment(AccountId, AccountId, Balance),
+        // sender, receiver, deposit, nonce, opened, expiration
+        ChannelOpened(AccountId, AccountId, Balance, u64, Timestamp, Timestamp),
+        // sender, receiver, time, how the channel was closed
+        ChannelClosed(AccountId, AccountId, Timestamp, CloseKind),
+        // sender, receiver, amount as signed (before RateProvider), settlement sequence number
+        ClaimPayment(AccountId, AccountId, Balance, u64),
+        // new total amount locked across all channels
+        TotalLockedChanged(Balance),
+        // sender, old receiver, new receiver, new nonce
+        ReceiverReassigned(AccountId, AccountId, AccountId, u64),
+        // sender, receiver, new expiration
+        ChannelExtended(AccountId, AccountId, Timestamp),
+        // sender, channels closed, channels of the sender not closed yet
+        CloseBatchProcessed(AccountId, u32, u32),
+        // receiver, vouchers passed to claim_best, vouchers validly signed, vouchers rejected
+        BatchProcessed(AccountId, u32, u32, u32),
+        // sender, receiver, session id burnt by the sender
+        SessionIdInvalidated(AccountId, AccountId, u32),
+        // sender, receiver, session id below which all sessions are consumed
+        SessionsSuperseded(AccountId, AccountId, u32),
+        // sender, receiver, time from which the sender can finalize the close
+        ChannelClosing(AccountId, AccountId, Timestamp),
+        // sender, receiver, new quota
+        ReceiverQuotaSet(AccountId, AccountId, Option<Balance>),
+        // payer, payee, net amount transferred by net_settle
+        NetSettled(AccountId, AccountId, Balance),
+        // sender, receiver, old deposit, corrected deposit
+        ChannelReconciled(AccountId, AccountId, Balance, Balance),
+        // sender, old ReceiverCount, corrected ReceiverCount
+        ChannelsRecounted(AccountId, u32, u32),
+        // sender, receiver, expiration; the channel is within WarningWindow of expiring
+        ChannelExpiringSoon(AccountId, AccountId, Timestamp),
+        // sender, receiver, amount going back to the sender once the close is finalized
+        SettlementIntent(AccountId, AccountId, Balance),
+        // receiver, account allowed to claim on its behalf
+        ClaimDelegateAdded(AccountId, AccountId),
+        // receiver, account no longer allowed to claim on its behalf
+        ClaimDelegateRemoved(AccountId, AccountId),
+        // claims made with claim_payment_quiet in the block, amount they paid out in total
+        QuietClaimsSettled(u32, Balance),
     }
 );
 
+// errors
+// decl_error! numbers the variants in declaration order and that number is the `error` field of
+// DispatchError::Module clients see, so new variants only ever go at the end:
+//   0 SignatureTooLong, 1 InvalidSignature, 2 NoValidVoucher, 3 OnlyReceiverCanClose,
+//   4 ClaimTooSmall, 5 ReceiverBelowExistentialDeposit, 6 PubkeyMismatch, 7 ChannelExpired,
+//   8 NotStreamChannel, 9 NothingToClaim, 10 SelfPayment, 11 VoucherExpired, 12 ChannelNotOpen,
+//   13 ChannelNotClosing, 14 ChallengePeriodNotOver, 15 ChannelSettled, 16 QuotaExceeded,
+//   17 QuotaBelowClaimed, 18 ClaimAboveMax, 19 InsufficientBalance, 20 UnsupportedSignatureScheme,
+//   21 SettlementPending, 22 NoPendingSettlement, 23 ClaimGracePeriodNotOver, 24 NotClaimDelegate,
+//   25 RateLimited, 26 SettlementWindowNotOver, 27 InsufficientReserve
+// errors raised as plain strings are DispatchError::Other and carry no code
+decl_error! {
+    pub enum Error for Module<T: Trait> {
+        /// Signature is longer than `MaxSignatureLen`.
+        SignatureTooLong,
+        /// Signature does not have the length of an sr25519 signature.
+        InvalidSignature,
+        /// None of the vouchers passed to `claim_best` carries a valid signature.
+        NoValidVoucher,
+        /// `close_channel` was called by the sender of the channel, only the receiver can close it.
+        OnlyReceiverCanClose,
+        /// Claimed amount is below `MinClaimAmount` and does not exhaust the channel.
+        ClaimTooSmall,
+        /// Receiver account does not exist and the claimed amount is below the existential deposit.
+        ReceiverBelowExistentialDeposit,
+        /// Public key passed to `claim_payment_with_pubkey` does not belong to the sender.
+        PubkeyMismatch,
+        /// Channel is already past its expiration and can only be closed.
+        ChannelExpired,
+        /// Channel has no streaming rate, it was not opened with `open_stream_channel`.
+        NotStreamChannel,
+        /// The claim is for a zero amount, e.g. nothing has accrued on the stream since the last
+        /// claim.
+        NothingToClaim,
+        /// `SameEntityCheck` considers sender and receiver the same party.
+        SelfPayment,
+        /// The voucher's `valid_until` block has passed.
+        VoucherExpired,
+        /// The channel is closing or settled, the call needs it to be open.
+        ChannelNotOpen,
+        /// `finalize_close` needs the sender to call `initiate_close` first.
+        ChannelNotClosing,
+        /// The receiver can still claim, the challenge period is not over.
+        ChallengePeriodNotOver,
+        /// The whole deposit has been claimed already.
+        ChannelSettled,
+        /// The claim would take the receiver past the quota the sender set.
+        QuotaExceeded,
+        /// A quota can not be set below what the receiver has claimed already.
+        QuotaBelowClaimed,
+        /// The voucher is for more than the hybrid channel's `max_per_claim`.
+        ClaimAboveMax,
+        /// The sender's free balance can not cover the deposit.
+        InsufficientBalance,
+        /// The call takes signatures of another scheme: sr25519, or ecdsa for
+        /// `claim_payment_with_pubkey`.
+        UnsupportedSignatureScheme,
+        /// The receiver already asked for a deferred close of the channel.
+        SettlementPending,
+        /// `finalize_settlement` needs a deferred `close_channel` first.
+        NoPendingSettlement,
+        /// The channel was opened less than `ClaimGracePeriod` blocks ago.
+        ClaimGracePeriodNotOver,
+        /// The caller is not a claim delegate of the receiver.
+        NotClaimDelegate,
+        /// The receiver made `GlobalClaimRate` claims in the current window already.
+        RateLimited,
+        /// The deferred close was asked for less than `SettlementWindow` blocks ago.
+        SettlementWindowNotOver,
+        /// The sender has less reserved than the payment, the channel's deposit is not backed.
+        InsufficientReserve,
+    }
+}
+
 // storage for this module
 decl_storage! {
   trait Store for Module<T: Trait> as Device {
       Channel get(fn get_channel): map hasher(blake2_128_concat) (T::AccountId, T::AccountId) => ChannelOf<T>;
       // nonce indicates the next available value; increase by one whenever open a new channel for an account pair
       Nonce get(fn get_nonce): map hasher(blake2_128_concat) (T::AccountId, T::AccountId)  => u64;
+      // session ids are picked by the sender and submitted by the receiver, they are not a
+      // trusted key space, so the second key keeps blake2_128_concat rather than twox_64_concat;
+      // hashing a u32 is a negligible part of a claim next to the sr25519 verification
       SessionId get(fn get_session_id): double_map hasher(blake2_128_concat) (T::AccountId, T::AccountId), hasher(blake2_128_concat) u32 => bool;
+      // session ids below the floor count as consumed, claim_aggregate raises it
+      SessionFloor get(fn session_floor): map hasher(blake2_128_concat) (T::AccountId, T::AccountId) => u32;
+      // receivers of the open channels of a sender
+      ChannelsOf get(fn channels_of): map hasher(blake2_128_concat) T::AccountId => Vec<T::AccountId>;
+      // length of ChannelsOf, kept separately so it can be read without decoding the vector
+      ReceiverCount get(fn receiver_count): map hasher(blake2_128_concat) T::AccountId => u32;
+      // streaming terms of channels opened with open_stream_channel
+      Streams get(fn get_stream): map hasher(blake2_128_concat) (T::AccountId, T::AccountId) => Option<StreamOf<T>>;
+      // sum of the unclaimed deposits of all open channels
+      TotalLocked get(fn total_locked): BalanceOf<T>;
+      // number of settlements so far across all channels, each ClaimPayment carries the next one
+      SettlementSeq get(fn settlement_seq): u64;
+      // expiration of every open channel not warned about yet, under the EXPIRY_BUCKET its
+      // expiration falls in, so on_initialize only reads the buckets coming due; channels
+      // already within WarningWindow when queued are warned about at once and never stored
+      ExpiryBuckets get(fn expiry_bucket): double_map hasher(blake2_128_concat) u64, hasher(blake2_128_concat) (T::AccountId, T::AccountId) => Moment<T>;
+      // first bucket that may still hold channels to warn about
+      ExpiryCursor get(fn expiry_cursor): u64;
+      // block in which the receiver asked for a deferred close, funds move in finalize_settlement
+      PendingSettlement get(fn pending_settlement): map hasher(blake2_128_concat) (T::AccountId, T::AccountId) => Option<T::BlockNumber>;
+      // latest claims of each channel by their sequence number in the channel
+      ClaimLog get(fn claim_log_entry): double_map hasher(blake2_128_concat) (T::AccountId, T::AccountId), hasher(twox_64_concat) u32 => Option<ClaimRecordOf<T>>;
+      // first sequence number still in ClaimLog and the one the next claim gets
+      ClaimLogRange get(fn claim_log_range): map hasher(blake2_128_concat) (T::AccountId, T::AccountId) => (u32, u32);
+      // block the current GlobalClaimRate window of a receiver started in and its claims since
+      ClaimWindow get(fn claim_window): map hasher(blake2_128_concat) T::AccountId => (T::BlockNumber, u32);
+      // claims made with claim_payment_quiet in the current block and the amount they paid out,
+      // reported and cleared in on_finalize
+      QuietClaims get(fn quiet_claims): (u32, BalanceOf<T>);
+      // accounts a receiver lets submit claims for it with delegate_claim
+      ClaimDelegates get(fn is_claim_delegate): double_map hasher(blake2_128_concat) T::AccountId, hasher(blake2_128_concat) T::AccountId => bool;
   }
 
 }
@@ -62,82 +453,1249 @@ decl_storage! {
 // public interface for this runtime module
 decl_module! {
   pub struct Module<T: Trait> for enum Call where origin: T::Origin {
+      type Error = Error<T>;
+
+      /// Longest signature accepted with a voucher.
+      const MaxSignatureLen: u32 = T::MaxSignatureLen::get();
+      /// Smallest amount a claim settles, unless it takes everything claimable.
+      const MinClaimAmount: BalanceOf<T> = T::MinClaimAmount::get();
+      /// Most channels `close_all_for_sender` looks at in one call.
+      const MaxCloseBatch: u32 = T::MaxCloseBatch::get();
+      /// Time the receiver has to claim after `initiate_close`, in milliseconds.
+      const ChallengePeriod: Moment<T> = T::ChallengePeriod::get();
+      /// How long before expiring a channel is announced, in milliseconds.
+      const WarningWindow: Moment<T> = T::WarningWindow::get();
+      /// Most expiry announcements per block.
+      const MaxExpiryWarnings: u32 = T::MaxExpiryWarnings::get();
+      /// Blocks after opening during which a channel can not be claimed from.
+      const ClaimGracePeriod: T::BlockNumber = T::ClaimGracePeriod::get();
+      /// Claims kept in the log of each channel.
+      const MaxClaimLog: u32 = T::MaxClaimLog::get();
+      /// Claims per receiver and the window, in blocks, they are counted over.
+      const GlobalClaimRate: (u32, T::BlockNumber) = T::GlobalClaimRate::get();
+      /// Whether voucher signatures are verified.
+      const RequireSignature: bool = T::RequireSignature::get();
+      /// Blocks between a deferred close and its settlement.
+      const SettlementWindow: T::BlockNumber = T::SettlementWindow::get();
+
       // initialize the default event for this module
       fn deposit_event() = default;
 
+      // warn about channels entering WarningWindow; this runs before the block's timestamp is
+      // set, so now is the previous block's time. At most MaxExpiryWarnings channels and
+      // MAX_EXPIRY_BUCKET_SCAN buckets are handled per block, the rest wait for the next blocks.
+      // Buckets behind the cursor are never read again, queue_expiry warns about expirations
+      // that fall in them right away
+      fn on_initialize(_n: T::BlockNumber) -> Weight {
+          let now = T::Timestamp::now();
+          let horizon = now.saturating_add(T::WarningWindow::get());
+          let last = Self::expiry_bucket_of(horizon);
+          let max = T::MaxExpiryWarnings::get() as usize;
+          let cursor = ExpiryCursor::get();
+          let mut bucket = cursor.max(Self::expiry_bucket_of(now));
+          let (mut scanned, mut read, mut due) = (0u64, 0u64, 0usize);
+          while bucket <= last && scanned < MAX_EXPIRY_BUCKET_SCAN && due < max {
+              scanned += 1;
+              // channels of the last bucket expiring after horizon are not due yet, they are
+              // skipped without counting against MaxExpiryWarnings
+              let mut entries: Vec<_> = ExpiryBuckets::<T>::iter_prefix(bucket)
+                  .inspect(|_| read += 1)
+                  .filter(|(_, at)| *at <= horizon)
+                  .take(max - due)
+                  .collect();
+              let exhausted = due + entries.len() < max;
+              due += entries.len();
+              entries.sort_by(|(_, a), (_, b)| a.cmp(b));
+              for ((sender, receiver), at) in entries {
+                  ExpiryBuckets::<T>::remove(bucket, (sender.clone(), receiver.clone()));
+                  if at >= now {
+                      Self::deposit_event(RawEvent::ChannelExpiringSoon(sender, receiver, at));
+                  }
+              }
+              // the last bucket is only partly due, channels can still come due in it
+              if bucket == last || !exhausted {
+                  break;
+              }
+              bucket += 1;
+          }
+          if bucket != cursor {
+              ExpiryCursor::put(bucket);
+          }
+          // plus on_finalize reading QuietClaims and TotalLocked and clearing QuietClaims
+          let reads = 1 + scanned + read + 2;
+          10_000u64.saturating_add(T::DbWeight::get().reads_writes(reads, due as u64 + 2))
+      }
+
+      // one event for all the claims claim_payment_quiet made in the block
+      fn on_finalize(_n: T::BlockNumber) {
+          let (count, total) = QuietClaims::<T>::take();
+          if count > 0 {
+              Self::deposit_event(RawEvent::QuietClaimsSettled(count, total));
+              Self::deposit_event(RawEvent::TotalLockedChanged(TotalLocked::<T>::get()));
+          }
+      }
+
       #[weight = 10_000]
       // duration is in units of second
-      // TODO: reserve enough tokens for micropayment when open the channel
-      pub fn open_channel(origin, receiver: T::AccountId, duration: u32) -> DispatchResult {
+      // lock_amt is reserved from the sender and is the most the receiver can ever claim
+      pub fn open_channel(origin, receiver: T::AccountId, lock_amt: BalanceOf<T>, duration: u32) -> DispatchResult {
+          let sender = T::OpenOrigin::ensure_origin(origin)?;
+          Self::do_open_channel(&sender, &receiver, lock_amt, duration)
+      }
+
+      #[weight = 10_000]
+      // like open_channel, but instead of signing vouchers the sender authorizes the receiver
+      // once to withdraw rate per block from the deposit with claim_stream
+      pub fn open_stream_channel(origin, receiver: T::AccountId, lock_amt: BalanceOf<T>, duration: u32, rate: BalanceOf<T>) -> DispatchResult {
+          let sender = T::OpenOrigin::ensure_origin(origin)?;
+          ensure!(!rate.is_zero(), Error::<T>::NotStreamChannel);
+          Self::do_open_channel(&sender, &receiver, lock_amt, duration)?;
+          let stream = StreamOf::<T> {
+              rate,
+              last_claim: frame_system::Module::<T>::block_number(),
+              max_per_claim: None,
+          };
+          Streams::<T>::insert((sender, receiver), stream);
+          Ok(())
+      }
+
+      #[weight = 10_000]
+      // a streaming channel on which the receiver can also claim vouchers of up to max_per_claim
+      // each; both ways of claiming draw from the same deposit
+      pub fn open_hybrid_channel(origin, receiver: T::AccountId, lock_amt: BalanceOf<T>, duration: u32, rate: BalanceOf<T>, max_per_claim: BalanceOf<T>) -> DispatchResult {
+          let sender = T::OpenOrigin::ensure_origin(origin)?;
+          ensure!(!rate.is_zero(), Error::<T>::NotStreamChannel);
+          Self::do_open_channel(&sender, &receiver, lock_amt, duration)?;
+          let stream = StreamOf::<T> {
+              rate,
+              last_claim: frame_system::Module::<T>::block_number(),
+              max_per_claim: Some(max_per_claim),
+          };
+          Streams::<T>::insert((sender, receiver), stream);
+          Ok(())
+      }
+
+      #[weight = 10_000]
+      // called by the sender to push the expiration of its channel to receiver back by extra,
+      // a channel past its expiration is waiting to be closed and can not be extended
+      pub fn extend_channel(origin, receiver: T::AccountId, extra: Moment<T>) -> DispatchResult {
+          let sender = ensure_signed(origin)?;
+          ensure!(Channel::<T>::contains_key((sender.clone(),receiver.clone())), "Channel not exists");
+          let chan = Channel::<T>::get((sender.clone(),receiver.clone()));
+          ensure!(chan.state == ChannelState::Open, Error::<T>::ChannelNotOpen);
+          ensure!(chan.expiration >= T::Timestamp::now(), Error::<T>::ChannelExpired);
+          let expiration = chan.expiration.saturating_add(extra);
+          Channel::<T>::mutate((sender.clone(),receiver.clone()), |c| c.expiration = expiration);
+          Self::unqueue_expiry(&sender, &receiver, chan.expiration);
+          Self::queue_expiry(&sender, &receiver, expiration);
+          Self::deposit_event(RawEvent::ChannelExtended(sender, receiver, expiration));
+          Ok(())
+      }
+
+      #[weight = 10_000]
+      // called by the sender to cap the total the receiver can claim from the channel below the
+      // deposit, or to lift the cap with None; only while the channel is open, a closing channel
+      // keeps its quota for the receiver's claims in the challenge period
+      pub fn set_receiver_quota(origin, receiver: T::AccountId, quota: Option<BalanceOf<T>>) -> DispatchResult {
+          let sender = ensure_signed(origin)?;
+          ensure!(Channel::<T>::contains_key((sender.clone(),receiver.clone())), "Channel not exists");
+          let chan = Channel::<T>::get((sender.clone(),receiver.clone()));
+          ensure!(chan.state == ChannelState::Open, Error::<T>::ChannelNotOpen);
+          ensure!(quota.map_or(true, |q| q >= chan.claimed), Error::<T>::QuotaBelowClaimed);
+          Channel::<T>::mutate((sender.clone(),receiver.clone()), |c| c.quota = quota);
+          Self::deposit_event(RawEvent::ReceiverQuotaSet(sender, receiver, quota));
+          Ok(())
+      }
+
+      #[weight = 10_000]
+      // called by the sender to close its channel to receiver without the receiver's help; the
+      // receiver gets ChallengePeriod to claim its outstanding vouchers before the sender can
+      // finalize_close and get the rest of the deposit back
+      pub fn initiate_close(origin, receiver: T::AccountId) -> DispatchResult {
+          let sender = ensure_signed(origin)?;
+          ensure!(Channel::<T>::contains_key((sender.clone(),receiver.clone())), "Channel not exists");
+          let chan = Channel::<T>::get((sender.clone(),receiver.clone()));
+          ensure!(chan.state == ChannelState::Open, Error::<T>::ChannelNotOpen);
+          Self::start_close(&sender, &receiver);
+          Ok(())
+      }
+
+      #[weight = 10_000]
+      // close a channel whose challenge period is over, that is settled, or that is still in its
+      // claim grace period, the unclaimed deposit goes back to the sender
+      pub fn finalize_close(origin, receiver: T::AccountId) -> DispatchResult {
           let sender = ensure_signed(origin)?;
-          ensure!(!Channel::<T>::contains_key((sender.clone(),receiver.clone())), "Channel already opened");
-          ensure!(sender.clone() != receiver.clone(), "Channel should connect two different accounts");
-          let nonce = Nonce::<T>::get((sender.clone(),receiver.clone()));
+          ensure!(Channel::<T>::contains_key((sender.clone(),receiver.clone())), "Channel not exists");
+          let chan = Channel::<T>::get((sender.clone(),receiver.clone()));
           let time = T::Timestamp::now();
-          let duration_in_mills = duration * 1000;
-          let expiration = time.saturating_add(duration_in_mills.into());
-          let chan = ChannelOf::<T>{
-              sender: sender.clone(),
-              receiver: receiver.clone(),
-              nonce: nonce.clone(),
-              opened: time.clone(),
-              expiration: expiration.clone(),
-          };
-          Channel::<T>::insert((sender.clone(),receiver.clone()), chan);
-          Nonce::<T>::insert((sender.clone(),receiver.clone()),nonce+1);
-          //Nonce::<T>::mutate((sender.clone(),receiver.clone()),|v|*v+1);
-          Self::deposit_event(RawEvent::ChannelOpened(sender,receiver,nonce, time,expiration));
+          match chan.state {
+              ChannelState::Closing { at } => ensure!(at <= time, Error::<T>::ChallengePeriodNotOver),
+              // nothing is left to claim, there is nothing to wait for
+              ChannelState::Settled => {}
+              // opened by mistake, the receiver could not claim anything yet
+              ChannelState::Open => ensure!(Self::in_grace_period(&chan), Error::<T>::ChannelNotClosing),
+          }
+          Self::_close_channel(&sender, &receiver);
+          Self::deposit_event(RawEvent::ChannelClosed(sender, receiver, time, CloseKind::Unilateral));
           Ok(())
       }
 
       #[weight = 10_000]
       // make sure claim your payment before close the channel
-      // TODO: refund the rest of reserved tokens back to sender
-      pub fn close_channel(origin, sender: T::AccountId) -> DispatchResult {
+      // the unclaimed part of the deposit is returned to the sender; with deferred the close is
+      // only announced and nothing moves until finalize_settlement
+      pub fn close_channel(origin, sender: T::AccountId, deferred: bool) -> DispatchResult {
           // only receiver can close the channel
           let receiver = ensure_signed(origin)?;
-          ensure!(Channel::<T>::contains_key((sender.clone(),receiver.clone())), "Channel not exists");
+          // a sender closing by mistake passes either itself or its receiver as `sender`;
+          // the sender gets its deposit back once the channel expires
+          let exists = Channel::<T>::contains_key((sender.clone(),receiver.clone()));
+          ensure!(
+              exists || (sender != receiver && !Channel::<T>::contains_key((receiver.clone(),sender.clone()))),
+              Error::<T>::OnlyReceiverCanClose
+          );
+          ensure!(exists, "Channel not exists");
+          ensure!(!PendingSettlement::<T>::contains_key((sender.clone(),receiver.clone())), Error::<T>::SettlementPending);
+          if deferred {
+              let chan = Channel::<T>::get((sender.clone(),receiver.clone()));
+              let now = frame_system::Module::<T>::block_number();
+              PendingSettlement::<T>::insert((sender.clone(),receiver.clone()), now);
+              Self::deposit_event(RawEvent::SettlementIntent(sender, receiver, chan.deposit.saturating_sub(chan.claimed)));
+              return Ok(());
+          }
+          Self::_close_channel(&sender, &receiver);
+          let time = T::Timestamp::now();
+          Self::deposit_event(RawEvent::ChannelClosed(sender, receiver, time, CloseKind::Cooperative));
+          Ok(())
+      }
+
+      #[weight = 10_000]
+      // carry out a deferred close_channel, anyone can submit it once the intent has been on
+      // chain for SettlementWindow blocks
+      pub fn finalize_settlement(origin, sender: T::AccountId, receiver: T::AccountId) -> DispatchResult {
+          let _ = ensure_signed(origin)?;
+          let requested = PendingSettlement::<T>::get((sender.clone(),receiver.clone())).ok_or(Error::<T>::NoPendingSettlement)?;
+          let now = frame_system::Module::<T>::block_number();
+          ensure!(now >= requested.saturating_add(T::SettlementWindow::get()), Error::<T>::SettlementWindowNotOver);
           Self::_close_channel(&sender, &receiver);
           let time = T::Timestamp::now();
-          Self::deposit_event(RawEvent::ChannelClosed(sender, receiver, time));
+          Self::deposit_event(RawEvent::ChannelClosed(sender, receiver, time, CloseKind::Cooperative));
           Ok(())
       }
 
+      #[weight = CLOSE_ALL_CHANNEL_WEIGHT.saturating_mul(T::MaxCloseBatch::get() as Weight)]
+      // called by a sender winding down, for each of the next MaxCloseBatch of its channels:
+      // expired channels are refunded, open ones start closing as with initiate_close, and
+      // closing ones past their challenge period or settled ones are finalized. Channels not
+      // closed yet move to the back, so calling again continues with the ones not looked at
+      // yet; once the challenge periods are over one more round closes them
+      // the caller pays for the channels actually looked at
+      pub fn close_all_for_sender(origin) -> DispatchResultWithPostInfo {
+          let sender = ensure_signed(origin)?;
+          let receivers = ChannelsOf::<T>::get(&sender);
+          let batch = (T::MaxCloseBatch::get() as usize).min(receivers.len());
+          let time = T::Timestamp::now();
+          let mut kept = Vec::new();
+          let mut closed = 0u32;
+          for receiver in receivers.iter().take(batch) {
+              if Self::close_if_expired(&sender, receiver) {
+                  closed += 1;
+                  continue;
+              }
+              match Channel::<T>::get((sender.clone(), receiver.clone())).state {
+                  ChannelState::Open => {
+                      Self::start_close(&sender, receiver);
+                      kept.push(receiver.clone());
+                  }
+                  ChannelState::Closing { at } if at > time => kept.push(receiver.clone()),
+                  ChannelState::Closing { .. } | ChannelState::Settled => {
+                      Self::_close_channel(&sender, receiver);
+                      Self::deposit_event(RawEvent::ChannelClosed(sender.clone(), receiver.clone(), time, CloseKind::Unilateral));
+                      closed += 1;
+                  }
+              }
+          }
+          let mut rest: Vec<T::AccountId> = receivers.into_iter().skip(batch).collect();
+          rest.extend(kept);
+          let open = rest.len() as u32;
+          if rest.is_empty() {
+              ChannelsOf::<T>::remove(&sender);
+          } else {
+              ChannelsOf::<T>::insert(&sender, rest);
+          }
+          Self::deposit_event(RawEvent::CloseBatchProcessed(sender, closed, open));
+          let used = (batch as Weight).max(1);
+          Ok(Some(CLOSE_ALL_CHANNEL_WEIGHT.saturating_mul(used)).into())
+      }
+
       #[weight = 10_000]
-      // TODO: instead of transfer from sender, transfer from sender's reserved token
-      pub fn claim_payment(origin, sender: T::AccountId, session_id: u32, amount: BalanceOf<T>, signature: Vec<u8>) -> DispatchResult {
+      // withdraw everything accrued on a streaming channel since the last claim, capped at what
+      // is left of the deposit; no voucher is needed, the sender agreed to the rate at open
+      pub fn claim_stream(origin, sender: T::AccountId) -> DispatchResult {
           let receiver = ensure_signed(origin)?;
           ensure!(Channel::<T>::contains_key((sender.clone(),receiver.clone())), "Channel not exists");
+          let stream = Streams::<T>::get((sender.clone(),receiver.clone())).ok_or(Error::<T>::NotStreamChannel)?;
+
+          if Self::close_if_expired(&sender, &receiver) {
+              return Ok(());
+          }
 
+          let now = frame_system::Module::<T>::block_number();
+          let amount = Self::stream_accrued(&sender, &receiver, &stream, now);
+          ensure!(!amount.is_zero(), Error::<T>::NothingToClaim);
+          Self::check_claim_amount(&sender, &receiver, amount)?;
+          Self::pay_out(&sender, &receiver, amount, amount, false)?;
+          Self::log_claim(&sender, &receiver, None, amount);
+          Streams::<T>::mutate((sender,receiver), |s| {
+              if let Some(s) = s {
+                  s.last_claim = now;
+              }
+          });
+          Ok(())
+      }
+
+      #[weight = 20_000]
+      // settle the latest vouchers of both channels between the caller and counterparty at once:
+      // to_counterparty is (session_id, amount, valid_until, signature) signed by the caller for
+      // its channel to counterparty, from_counterparty the same the other way round; both are
+      // booked in full but only the difference is transferred.
+      // The caller signs to_counterparty itself, so approval is counterparty's signature over the
+      // net settle hash of both vouchers: without it the caller could settle a session it already
+      // owes more for with a smaller voucher
+      pub fn net_settle(origin, counterparty: T::AccountId, to_counterparty: NetVoucherOf<T>, from_counterparty: NetVoucherOf<T>, approval: TypedSignature) -> DispatchResult {
+          let caller = ensure_signed(origin)?;
+          let (a_session, a_amount, a_valid_until, a_signature) = to_counterparty;
+          let (b_session, b_amount, b_valid_until, b_signature) = from_counterparty;
+          let (a_signature, b_signature) = (Self::sr25519_bytes(a_signature)?, Self::sr25519_bytes(b_signature)?);
+          Self::check_voucher(&caller, &counterparty, a_session, a_amount, a_valid_until, &a_signature)?;
+          Self::check_voucher(&counterparty, &caller, b_session, b_amount, b_valid_until, &b_signature)?;
+          let approval = Self::sr25519_bytes(approval)?;
+          if T::RequireSignature::get() {
+              let a_nonce = Channel::<T>::get((caller.clone(),counterparty.clone())).nonce;
+              let b_nonce = Channel::<T>::get((counterparty.clone(),caller.clone())).nonce;
+              let msg = Self::construct_net_settle_hash(&caller, &counterparty, (a_nonce, a_session, a_amount), (b_nonce, b_session, b_amount));
+              Self::verify_sr25519(&counterparty, &msg, &approval)?;
+          }
+          let (a_paid, b_paid) = (T::RateProvider::to_settlement(a_amount), T::RateProvider::to_settlement(b_amount));
+          Self::check_voucher_amount(&caller, &counterparty, a_paid)?;
+          Self::check_voucher_amount(&counterparty, &caller, b_paid)?;
+
+          let offset = a_paid.min(b_paid);
+          let (payer, payee, net) = if a_paid >= b_paid {
+              (caller.clone(), counterparty.clone(), a_paid - b_paid)
+          } else {
+              (counterparty.clone(), caller.clone(), b_paid - a_paid)
+          };
+          if !net.is_zero() {
+              Self::pay_from_reserve(&payer, &payee, net)?;
+          }
+          // the part both owe each other goes back to their own free balance
+          T::Currency::unreserve(&caller, offset);
+          T::Currency::unreserve(&counterparty, offset);
+
+          Self::record_claim(&caller, &counterparty, a_amount, a_paid, false);
+          Self::record_claim(&counterparty, &caller, b_amount, b_paid, false);
+          Self::log_claim(&caller, &counterparty, Some(a_session), a_paid);
+          Self::log_claim(&counterparty, &caller, Some(b_session), b_paid);
+          SessionId::<T>::insert((caller.clone(), counterparty.clone()), a_session, true);
+          SessionId::<T>::insert((counterparty.clone(), caller.clone()), b_session, true);
+          Self::deposit_event(RawEvent::NetSettled(payer, payee, net));
+          Ok(())
+      }
 
-          // close channel if it expires
+      #[weight = 10_000]
+      // repair a channel whose deposit is no longer backed by the sender's reserved balance, e.g.
+      // after a faulty migration: if the sender has less reserved than the unclaimed deposits of
+      // all its channels, this channel's deposit is lowered by the shortfall. A surplus is left
+      // alone, it may be reserved by other pallets
+      pub fn reconcile_channel(origin, sender: T::AccountId, receiver: T::AccountId) -> DispatchResult {
+          T::ForceOrigin::ensure_origin(origin)?;
+          ensure!(Channel::<T>::contains_key((sender.clone(),receiver.clone())), "Channel not exists");
+          let expected = ChannelsOf::<T>::get(&sender)
+              .iter()
+              .map(|r| {
+                  let c = Channel::<T>::get((sender.clone(), r.clone()));
+                  c.deposit.saturating_sub(c.claimed)
+              })
+              .fold(BalanceOf::<T>::zero(), |acc, r| acc.saturating_add(r));
+          let shortfall = expected.saturating_sub(T::Currency::reserved_balance(&sender));
           let chan = Channel::<T>::get((sender.clone(),receiver.clone()));
-          if chan.expiration < T::Timestamp::now() {
-              Self::_close_channel(&sender, &receiver);
-              let time = T::Timestamp::now();
-              Self::deposit_event(RawEvent::ChannelClosed(sender, receiver, time));
+          let remaining = chan.deposit.saturating_sub(chan.claimed);
+          let cut = shortfall.min(remaining);
+          let deposit = chan.deposit.saturating_sub(cut);
+          if !cut.is_zero() {
+              Channel::<T>::mutate((sender.clone(),receiver.clone()), |c| {
+                  c.deposit = deposit;
+                  if c.claimed >= c.deposit {
+                      c.state = ChannelState::Settled;
+                  }
+              });
+              Self::decrease_locked(cut);
+          }
+          Self::deposit_event(RawEvent::ChannelReconciled(sender, receiver, chan.deposit, deposit));
+          Ok(())
+      }
+
+      #[weight = 10_000]
+      // close a channel whatever its state, e.g. one stuck after a dispute; the unclaimed
+      // deposit goes back to the sender
+      pub fn force_close_channel(origin, sender: T::AccountId, receiver: T::AccountId) -> DispatchResult {
+          T::ForceOrigin::ensure_origin(origin)?;
+          ensure!(Channel::<T>::contains_key((sender.clone(),receiver.clone())), "Channel not exists");
+          Self::_close_channel(&sender, &receiver);
+          let time = T::Timestamp::now();
+          Self::deposit_event(RawEvent::ChannelClosed(sender, receiver, time, CloseKind::Forced));
+          Ok(())
+      }
+
+      #[weight = 10_000]
+      // set ReceiverCount of sender back to the length of its ChannelsOf list
+      pub fn recount_channels(origin, sender: T::AccountId) -> DispatchResult {
+          T::ForceOrigin::ensure_origin(origin)?;
+          let old = ReceiverCount::<T>::get(&sender);
+          let count = ChannelsOf::<T>::decode_len(&sender).unwrap_or(0) as u32;
+          if count == 0 {
+              ReceiverCount::<T>::remove(&sender);
+          } else {
+              ReceiverCount::<T>::insert(&sender, count);
+          }
+          Self::deposit_event(RawEvent::ChannelsRecounted(sender, old, count));
+          Ok(())
+      }
+
+      #[weight = 10_000]
+      // called by the sender to mark session_id as consumed without paying anything, so a
+      // leaked voucher for that session can not be claimed any more. Only an open channel can
+      // have sessions revoked: once the sender starts closing, the receiver's outstanding
+      // vouchers stay claimable for the challenge period, so receivers must claim what they are
+      // owed before the sender can no longer revoke it
+      pub fn invalidate_session_id(origin, receiver: T::AccountId, session_id: u32) -> DispatchResult {
+          let sender = ensure_signed(origin)?;
+          ensure!(Channel::<T>::contains_key((sender.clone(),receiver.clone())), "Channel not exists");
+          let chan = Channel::<T>::get((sender.clone(),receiver.clone()));
+          ensure!(chan.state == ChannelState::Open, Error::<T>::ChannelNotOpen);
+          ensure!(!Self::session_consumed(&sender, &receiver, session_id), "SessionID already consumed");
+          SessionId::<T>::insert((sender.clone(),receiver.clone()), session_id, true);
+          Self::deposit_event(RawEvent::SessionIdInvalidated(sender, receiver, session_id));
+          Ok(())
+      }
+
+      #[weight = 10_000]
+      // payment is taken from the sender's reserved deposit
+      pub fn claim_payment(origin, sender: T::AccountId, session_id: u32, amount: BalanceOf<T>, valid_until: T::BlockNumber, signature: TypedSignature) -> DispatchResult {
+          let receiver = ensure_signed(origin)?;
+          Self::claim_payment_to(&sender, &receiver, session_id, amount, valid_until, signature)
+      }
+
+      #[weight = 20_000]
+      // the usual way out of a channel for its receiver in one call: claim the latest voucher,
+      // then close the channel and refund the rest of the deposit to the sender; nothing
+      // happens unless the voucher can be claimed
+      pub fn settle_and_close(origin, sender: T::AccountId, session_id: u32, amount: BalanceOf<T>, valid_until: T::BlockNumber, signature: TypedSignature) -> DispatchResult {
+          let receiver = ensure_signed(origin)?;
+          let signature = Self::sr25519_bytes(signature)?;
+          ensure!(Channel::<T>::contains_key((sender.clone(),receiver.clone())), "Channel not exists");
+
+          if Self::close_if_expired(&sender, &receiver) {
+              return Ok(());
+          }
+
+          Self::settle_voucher(&sender, &receiver, session_id, amount, valid_until, &signature)?;
+          Self::_close_channel(&sender, &receiver);
+          let time = T::Timestamp::now();
+          Self::deposit_event(RawEvent::ChannelClosed(sender, receiver, time, CloseKind::Cooperative));
+          Ok(())
+      }
+
+      #[weight = 10_000]
+      // claim_payment without the per-claim events, for receivers settling very often; the
+      // claims of a block are reported together by one QuietClaimsSettled when it ends
+      pub fn claim_payment_quiet(origin, sender: T::AccountId, session_id: u32, amount: BalanceOf<T>, valid_until: T::BlockNumber, signature: TypedSignature) -> DispatchResult {
+          let receiver = ensure_signed(origin)?;
+          let signature = Self::sr25519_bytes(signature)?;
+          ensure!(Channel::<T>::contains_key((sender.clone(),receiver.clone())), "Channel not exists");
+
+          if Self::close_if_expired(&sender, &receiver) {
+              return Ok(());
+          }
+
+          Self::check_voucher(&sender, &receiver, session_id, amount, valid_until, &signature)?;
+          Self::settle(&sender, &receiver, session_id, amount, true)
+      }
+
+      #[weight = 10_000]
+      // claim_payment submitted by a claim delegate of receiver, the payment goes to receiver
+      pub fn delegate_claim(origin, receiver: T::AccountId, sender: T::AccountId, session_id: u32, amount: BalanceOf<T>, valid_until: T::BlockNumber, signature: TypedSignature) -> DispatchResult {
+          let delegate = ensure_signed(origin)?;
+          ensure!(ClaimDelegates::<T>::get(&receiver, &delegate), Error::<T>::NotClaimDelegate);
+          Self::claim_payment_to(&sender, &receiver, session_id, amount, valid_until, signature)
+      }
+
+      #[weight = 10_000]
+      // let delegate submit claims for the caller through delegate_claim
+      pub fn add_claim_delegate(origin, delegate: T::AccountId) -> DispatchResult {
+          let receiver = ensure_signed(origin)?;
+          ClaimDelegates::<T>::insert(&receiver, &delegate, true);
+          Self::deposit_event(RawEvent::ClaimDelegateAdded(receiver, delegate));
+          Ok(())
+      }
+
+      #[weight = 10_000]
+      // revoke a delegate added with add_claim_delegate
+      pub fn remove_claim_delegate(origin, delegate: T::AccountId) -> DispatchResult {
+          let receiver = ensure_signed(origin)?;
+          ensure!(ClaimDelegates::<T>::get(&receiver, &delegate), Error::<T>::NotClaimDelegate);
+          ClaimDelegates::<T>::remove(&receiver, &delegate);
+          Self::deposit_event(RawEvent::ClaimDelegateRemoved(receiver, delegate));
+          Ok(())
+      }
+
+      #[weight = 10_000]
+      // same as claim_payment for a sender signing with a secp256k1 key: the ecdsa signature is
+      // checked against pubkey, which has to map to sender through AccountFromPubkey, since the
+      // key can not be told from the account id
+      pub fn claim_payment_with_pubkey(origin, sender: T::AccountId, pubkey: [u8; 33], session_id: u32, amount: BalanceOf<T>, valid_until: T::BlockNumber, signature: TypedSignature) -> DispatchResult {
+          let receiver = ensure_signed(origin)?;
+          let signature = Self::ecdsa_signature(signature)?;
+          ensure!(T::AccountFromPubkey::convert(pubkey) == sender, Error::<T>::PubkeyMismatch);
+          ensure!(Channel::<T>::contains_key((sender.clone(),receiver.clone())), "Channel not exists");
+
+          if Self::close_if_expired(&sender, &receiver) {
+              return Ok(());
+          }
+
+          Self::check_voucher_with(&sender, &receiver, session_id, amount, valid_until, |msg| Self::verify_ecdsa(&pubkey, msg, &signature))?;
+          Self::settle(&sender, &receiver, session_id, amount, false)
+      }
+
+      #[weight = 10_000]
+      // settle one voucher that covers everything the sender owes for the sessions up to
+      // session_id; every lower session id is consumed with it, so vouchers the sender signed
+      // for them can not be claimed any more
+      pub fn claim_aggregate(origin, sender: T::AccountId, session_id: u32, amount: BalanceOf<T>, valid_until: T::BlockNumber, signature: TypedSignature) -> DispatchResult {
+          let receiver = ensure_signed(origin)?;
+          let signature = Self::sr25519_bytes(signature)?;
+          ensure!(Channel::<T>::contains_key((sender.clone(),receiver.clone())), "Channel not exists");
+
+          if Self::close_if_expired(&sender, &receiver) {
               return Ok(());
           }
 
-          ensure!(!SessionId::<T>::contains_key((sender.clone(),receiver.clone()),session_id), "SessionID already consumed");
-          Self::verify_signature(&sender, &receiver, chan.nonce, session_id, amount, &signature)?;
+          Self::settle_voucher(&sender, &receiver, session_id, amount, valid_until, &signature)?;
+          SessionFloor::<T>::insert((sender.clone(),receiver.clone()), session_id);
+          Self::deposit_event(RawEvent::SessionsSuperseded(sender, receiver, session_id));
+          Ok(())
+      }
+
+      #[weight = CLAIM_BEST_VOUCHER_WEIGHT.saturating_mul(vouchers.len() as Weight)]
+      // vouchers are (amount, valid_until, signature) for the same session_id; invalid or expired
+      // ones are skipped and only the largest validly signed amount is settled
+      // the caller pays for the vouchers whose signature was actually verified, the rest of the
+      // declared weight is refunded
+      pub fn claim_best(origin, sender: T::AccountId, session_id: u32, vouchers: Vec<(BalanceOf<T>, T::BlockNumber, TypedSignature)>) -> DispatchResultWithPostInfo {
+          let receiver = ensure_signed(origin)?;
+          ensure!(!vouchers.is_empty(), Error::<T>::NoValidVoucher);
+          ensure!(Channel::<T>::contains_key((sender.clone(),receiver.clone())), "Channel not exists");
+
+          // nothing gets verified when the channel is closed for expiring
+          if Self::close_if_expired(&sender, &receiver) {
+              return Ok(Some(CLAIM_BEST_VOUCHER_WEIGHT).into());
+          }
+
+          ensure!(!Self::session_consumed(&sender, &receiver, session_id), "SessionID already consumed");
+          let chan = Channel::<T>::get((sender.clone(),receiver.clone()));
+          // signatures of another scheme than sr25519 are dropped without any crypto work
+          let checked: Vec<_> = vouchers
+              .iter()
+              .filter_map(|(amount, valid_until, signature)| match signature {
+                  TypedSignature::Sr25519(sig) => Some((*amount, *valid_until, sig)),
+                  TypedSignature::Ecdsa(_) => None,
+              })
+              .collect();
+          let valid: Vec<_> = checked
+              .iter()
+              .filter(|(amount, valid_until, sig)| {
+                  if !T::RequireSignature::get() {
+                      return Self::ensure_voucher_live(*valid_until).is_ok();
+                  }
+                  Self::verify_signature(&sender, &receiver, chan.nonce, session_id, *amount, *valid_until, &sig.0).is_ok()
+              })
+              .map(|(amount, _, _)| *amount)
+              .collect();
+          let best = valid.iter().copied().max().ok_or(Error::<T>::NoValidVoucher)?;
+          Self::settle(&sender, &receiver, session_id, best, false)?;
+          let (total, succeeded) = (vouchers.len() as u32, valid.len() as u32);
+          Self::deposit_event(RawEvent::BatchProcessed(receiver, total, succeeded, total - succeeded));
+          let used = (checked.len() as Weight).max(1);
+          Ok(Some(CLAIM_BEST_VOUCHER_WEIGHT.saturating_mul(used)).into())
+      }
+
+      #[weight = 10_000]
+      // move a channel to new_receiver, signature is from old_receiver over the reassign hash;
+      // the channel gets the next nonce of (sender, new_receiver), so vouchers the sender signed
+      // for old_receiver can not be claimed any more and the sender has to sign new ones
+      pub fn reassign_receiver(origin, sender: T::AccountId, old_receiver: T::AccountId, new_receiver: T::AccountId, signature: TypedSignature) -> DispatchResult {
+          let _ = ensure_signed(origin)?;
+          let signature = Self::sr25519_bytes(signature)?;
+          ensure!(Channel::<T>::contains_key((sender.clone(),old_receiver.clone())), "Channel not exists");
+          ensure!(!Channel::<T>::contains_key((sender.clone(),new_receiver.clone())), "Channel already opened");
+          ensure!(sender != new_receiver, "Channel should connect two different accounts");
+          ensure!(!T::SameEntityCheck::is_same_entity(&sender, &new_receiver), Error::<T>::SelfPayment);
+          ensure!(!PendingSettlement::<T>::contains_key((sender.clone(),old_receiver.clone())), Error::<T>::SettlementPending);
+
+          let mut chan = Channel::<T>::get((sender.clone(),old_receiver.clone()));
+          let msg = Self::construct_reassign_hash(&sender, &new_receiver, chan.nonce);
+          Self::verify_sr25519(&old_receiver, &msg, &signature)?;
 
-          T::Currency::transfer(&sender, &receiver, amount, AllowDeath)?; // TODO: check what is AllowDeath
-          SessionId::<T>::insert((sender.clone(),receiver.clone()), session_id, true); // mark session_id as used
-          Self::deposit_event(RawEvent::ClaimPayment(sender, receiver, amount));
+          let nonce = Nonce::<T>::get((sender.clone(),new_receiver.clone()));
+          chan.receiver = new_receiver.clone();
+          chan.nonce = nonce;
+          // consumed session ids only matter for vouchers bound to old_receiver
+          SessionId::<T>::remove_prefix((sender.clone(),old_receiver.clone()));
+          SessionFloor::<T>::remove((sender.clone(),old_receiver.clone()));
+          ClaimLog::<T>::remove_prefix((sender.clone(),old_receiver.clone()));
+          ClaimLogRange::<T>::remove((sender.clone(),old_receiver.clone()));
+          Channel::<T>::remove((sender.clone(),old_receiver.clone()));
+          ChannelsOf::<T>::mutate(&sender, |receivers| {
+              for r in receivers.iter_mut().filter(|r| **r == old_receiver) {
+                  *r = new_receiver.clone();
+              }
+          });
+          Self::unqueue_expiry(&sender, &old_receiver, chan.expiration);
+          Self::queue_expiry(&sender, &new_receiver, chan.expiration);
+          Channel::<T>::insert((sender.clone(),new_receiver.clone()), chan);
+          if let Some(stream) = Streams::<T>::take((sender.clone(),old_receiver.clone())) {
+              Streams::<T>::insert((sender.clone(),new_receiver.clone()), stream);
+          }
+          Nonce::<T>::insert((sender.clone(),new_receiver.clone()), nonce+1);
+          Self::deposit_event(RawEvent::ReceiverReassigned(sender, old_receiver, new_receiver, nonce));
           Ok(())
       }
   }
 }
 
 impl<T: Trait> Module<T> {
+    fn do_open_channel(
+        sender: &T::AccountId,
+        receiver: &T::AccountId,
+        lock_amt: BalanceOf<T>,
+        duration: u32,
+    ) -> DispatchResult {
+        ensure!(
+            !Channel::<T>::contains_key((sender.clone(), receiver.clone())),
+            "Channel already opened"
+        );
+        ensure!(
+            sender != receiver,
+            "Channel should connect two different accounts"
+        );
+        ensure!(
+            !T::SameEntityCheck::is_same_entity(sender, receiver),
+            Error::<T>::SelfPayment
+        );
+        ensure!(
+            T::Currency::can_reserve(sender, lock_amt),
+            Error::<T>::InsufficientBalance
+        );
+        T::Currency::reserve(sender, lock_amt)?;
+        let nonce = Nonce::<T>::get((sender.clone(), receiver.clone()));
+        let time = T::Timestamp::now();
+        let duration_in_mills = duration * 1000;
+        let expiration = time.saturating_add(duration_in_mills.into());
+        let chan = ChannelOf::<T> {
+            sender: sender.clone(),
+            receiver: receiver.clone(),
+            nonce,
+            opened: time.clone(),
+            opened_at: frame_system::Module::<T>::block_number(),
+            expiration: expiration.clone(),
+            deposit: lock_amt,
+            claimed: BalanceOf::<T>::default(),
+            state: ChannelState::Open,
+            quota: None,
+        };
+        Channel::<T>::insert((sender.clone(), receiver.clone()), chan);
+        Nonce::<T>::insert((sender.clone(), receiver.clone()), nonce + 1);
+        ChannelsOf::<T>::mutate(sender, |receivers| receivers.push(receiver.clone()));
+        ReceiverCount::<T>::mutate(sender, |count| *count = count.saturating_add(1));
+        Self::deposit_event(RawEvent::ChannelOpened(
+            sender.clone(),
+            receiver.clone(),
+            lock_amt,
+            nonce,
+            time,
+            expiration,
+        ));
+        Self::increase_locked(lock_amt);
+        Self::queue_expiry(sender, receiver, expiration);
+        T::OnChannelOpened::on_channel_opened(sender, receiver, lock_amt);
+        Ok(())
+    }
+
+    // verify a voucher from sender and pay amount to receiver out of their channel, consuming
+    // session_id; for pallets settling micropayments inside their own calls. Unlike claim_payment
+    // an expired channel is an error and is left for the receiver to close
+    pub fn settle_voucher(
+        sender: &T::AccountId,
+        receiver: &T::AccountId,
+        session_id: u32,
+        amount: BalanceOf<T>,
+        valid_until: T::BlockNumber,
+        signature: &[u8],
+    ) -> DispatchResult {
+        Self::check_voucher(sender, receiver, session_id, amount, valid_until, signature)?;
+        Self::settle(sender, receiver, session_id, amount, false)
+    }
+
+    // raw bytes of a voucher signature for the calls verifying it against the sender's account
+    fn sr25519_bytes(signature: TypedSignature) -> Result<Vec<u8>, Error<T>> {
+        match signature {
+            TypedSignature::Sr25519(sig) => Ok(sig.0.to_vec()),
+            TypedSignature::Ecdsa(_) => Err(Error::<T>::UnsupportedSignatureScheme),
+        }
+    }
+
+    // signature passed to claim_payment_with_pubkey
+    fn ecdsa_signature(signature: TypedSignature) -> Result<ecdsa::Signature, Error<T>> {
+        match signature {
+            TypedSignature::Ecdsa(sig) => Ok(sig),
+            TypedSignature::Sr25519(_) => Err(Error::<T>::UnsupportedSignatureScheme),
+        }
+    }
+
+    // body of claim_payment and delegate_claim, receiver is the account paid
+    fn claim_payment_to(
+        sender: &T::AccountId,
+        receiver: &T::AccountId,
+        session_id: u32,
+        amount: BalanceOf<T>,
+        valid_until: T::BlockNumber,
+        signature: TypedSignature,
+    ) -> DispatchResult {
+        let signature = Self::sr25519_bytes(signature)?;
+        ensure!(
+            Channel::<T>::contains_key((sender.clone(), receiver.clone())),
+            "Channel not exists"
+        );
+
+        // close channel if it expires
+        if Self::close_if_expired(sender, receiver) {
+            return Ok(());
+        }
+
+        Self::settle_voucher(
+            sender,
+            receiver,
+            session_id,
+            amount,
+            valid_until,
+            &signature,
+        )
+    }
+
+    // everything settle_voucher checks before paying: the channel is open and not expired, the
+    // session is unused and the voucher is signed by sender, unless RequireSignature is off
+    fn check_voucher(
+        sender: &T::AccountId,
+        receiver: &T::AccountId,
+        session_id: u32,
+        amount: BalanceOf<T>,
+        valid_until: T::BlockNumber,
+        signature: &[u8],
+    ) -> DispatchResult {
+        ensure!(
+            signature.len() <= T::MaxSignatureLen::get() as usize,
+            Error::<T>::SignatureTooLong
+        );
+        Self::check_voucher_with(sender, receiver, session_id, amount, valid_until, |msg| {
+            Self::verify_sr25519(sender, msg, signature)
+        })
+    }
+
+    // check_voucher with verify checking the signature on the voucher hash
+    fn check_voucher_with<F>(
+        sender: &T::AccountId,
+        receiver: &T::AccountId,
+        session_id: u32,
+        amount: BalanceOf<T>,
+        valid_until: T::BlockNumber,
+        verify: F,
+    ) -> DispatchResult
+    where
+        F: FnOnce(&[u8; 32]) -> DispatchResult,
+    {
+        ensure!(
+            Channel::<T>::contains_key((sender.clone(), receiver.clone())),
+            "Channel not exists"
+        );
+        let chan = Channel::<T>::get((sender.clone(), receiver.clone()));
+        ensure!(
+            chan.expiration >= T::Timestamp::now(),
+            Error::<T>::ChannelExpired
+        );
+        ensure!(
+            !Self::session_consumed(sender, receiver, session_id),
+            "SessionID already consumed"
+        );
+        Self::ensure_voucher_live(valid_until)?;
+        if !T::RequireSignature::get() {
+            return Ok(());
+        }
+        let voucher = Self::voucher_of(
+            sender,
+            receiver,
+            chan.nonce,
+            session_id,
+            amount,
+            valid_until,
+        );
+        verify(&Self::voucher_hash(&voucher))
+    }
+
+    // EXPIRY_BUCKET the expiration falls in
+    fn expiry_bucket_of(expiration: Moment<T>) -> u64 {
+        expiration.saturated_into::<u64>() / EXPIRY_BUCKET
+    }
+
+    // an expiration already within WarningWindow may fall in a bucket behind ExpiryCursor, so
+    // the warning is given here instead of by on_initialize
+    fn queue_expiry(sender: &T::AccountId, receiver: &T::AccountId, expiration: Moment<T>) {
+        let now = T::Timestamp::now();
+        if expiration <= now.saturating_add(T::WarningWindow::get()) {
+            if expiration >= now {
+                Self::deposit_event(RawEvent::ChannelExpiringSoon(
+                    sender.clone(),
+                    receiver.clone(),
+                    expiration,
+                ));
+            }
+            return;
+        }
+        ExpiryBuckets::<T>::insert(
+            Self::expiry_bucket_of(expiration),
+            (sender.clone(), receiver.clone()),
+            expiration,
+        );
+    }
+
+    fn unqueue_expiry(sender: &T::AccountId, receiver: &T::AccountId, expiration: Moment<T>) {
+        ExpiryBuckets::<T>::remove(
+            Self::expiry_bucket_of(expiration),
+            (sender.clone(), receiver.clone()),
+        );
+    }
+
+    // amount a streaming channel owes its receiver at block now, capped at the deposit left
+    fn stream_accrued(
+        sender: &T::AccountId,
+        receiver: &T::AccountId,
+        stream: &StreamOf<T>,
+        now: T::BlockNumber,
+    ) -> BalanceOf<T> {
+        let chan = Channel::<T>::get((sender.clone(), receiver.clone()));
+        let blocks: u32 = now.saturating_sub(stream.last_claim).saturated_into();
+        stream
+            .rate
+            .saturating_mul(blocks.into())
+            .min(Self::claimable(&chan))
+    }
+
+    // GlobalClaimRate window of receiver at the current block, reset once it has run out
+    fn current_claim_window(receiver: &T::AccountId) -> (T::BlockNumber, u32) {
+        let now = frame_system::Module::<T>::block_number();
+        let (_, window) = T::GlobalClaimRate::get();
+        let (start, count) = ClaimWindow::<T>::get(receiver);
+        if now >= start.saturating_add(window) {
+            (now, 0)
+        } else {
+            (start, count)
+        }
+    }
+
+    // receiver can make one more claim in its GlobalClaimRate window
+    fn ensure_claim_rate(receiver: &T::AccountId) -> DispatchResult {
+        let (max, window) = T::GlobalClaimRate::get();
+        if window.is_zero() {
+            return Ok(());
+        }
+        let (_, count) = Self::current_claim_window(receiver);
+        ensure!(count < max, Error::<T>::RateLimited);
+        Ok(())
+    }
+
+    fn count_claim(receiver: &T::AccountId) {
+        if T::GlobalClaimRate::get().1.is_zero() {
+            return;
+        }
+        let (start, count) = Self::current_claim_window(receiver);
+        ClaimWindow::<T>::insert(receiver, (start, count.saturating_add(1)));
+    }
+
+    // give the receiver ChallengePeriod to claim before the sender can finalize the close
+    fn start_close(sender: &T::AccountId, receiver: &T::AccountId) {
+        let at = T::Timestamp::now().saturating_add(T::ChallengePeriod::get());
+        Channel::<T>::mutate((sender.clone(), receiver.clone()), |c| {
+            c.state = ChannelState::Closing { at }
+        });
+        Self::deposit_event(RawEvent::ChannelClosing(
+            sender.clone(),
+            receiver.clone(),
+            at,
+        ));
+    }
+
+    // whether chan was opened less than ClaimGracePeriod blocks ago
+    fn in_grace_period(chan: &ChannelOf<T>) -> bool {
+        frame_system::Module::<T>::block_number()
+            < chan.opened_at.saturating_add(T::ClaimGracePeriod::get())
+    }
+
+    // what the receiver can still claim: the deposit left, or less if a quota is set
+    fn claimable(chan: &ChannelOf<T>) -> BalanceOf<T> {
+        let remaining = chan.deposit.saturating_sub(chan.claimed);
+        match chan.quota {
+            Some(quota) => remaining.min(quota.saturating_sub(chan.claimed)),
+            None => remaining,
+        }
+    }
+
+    // most the receiver can still claim from sender, zero without an open channel
+    pub fn remaining_claimable(sender: &T::AccountId, receiver: &T::AccountId) -> BalanceOf<T> {
+        if !Channel::<T>::contains_key((sender.clone(), receiver.clone())) {
+            return Zero::zero();
+        }
+        Self::claimable(&Channel::<T>::get((sender.clone(), receiver.clone())))
+    }
+
+    // whether sender started closing the channel and the receiver can still claim before
+    // finalize_close, false for open and settled channels
+    pub fn is_in_challenge_window(sender: &T::AccountId, receiver: &T::AccountId) -> bool {
+        if !Channel::<T>::contains_key((sender.clone(), receiver.clone())) {
+            return false;
+        }
+        match Channel::<T>::get((sender.clone(), receiver.clone())).state {
+            ChannelState::Closing { at } => T::Timestamp::now() < at,
+            _ => false,
+        }
+    }
+
+    // nonce of the open channel from sender to receiver, wallets sign vouchers with it
+    pub fn channel_epoch(sender: &T::AccountId, receiver: &T::AccountId) -> Option<u64> {
+        if !Channel::<T>::contains_key((sender.clone(), receiver.clone())) {
+            return None;
+        }
+        Some(Channel::<T>::get((sender.clone(), receiver.clone())).nonce)
+    }
+
+    // consolidated view of a pair, None if no channel was ever opened between them
+    pub fn pair_summary(
+        sender: &T::AccountId,
+        receiver: &T::AccountId,
+    ) -> Option<PairSummaryOf<T>> {
+        let key = (sender.clone(), receiver.clone());
+        let epoch = Nonce::<T>::get(&key);
+        if epoch == 0 {
+            return None;
+        }
+        let channel = if Channel::<T>::contains_key(&key) {
+            Some(Channel::<T>::get(&key))
+        } else {
+            None
+        };
+        let claimed = channel
+            .as_ref()
+            .map(|c| c.claimed)
+            .unwrap_or_else(Zero::zero);
+        let below_floor = SessionFloor::<T>::get(&key).checked_sub(1);
+        let highest_session_id = SessionId::<T>::iter_prefix(&key)
+            .map(|(id, _)| id)
+            .chain(below_floor)
+            .max();
+        Some(PairSummary {
+            channel,
+            epoch,
+            claimed,
+            highest_session_id,
+        })
+    }
+
+    // session ids in start..start+count that are already consumed, count is capped at
+    // MAX_SESSION_SCAN to bound the number of storage reads
+    pub fn consumed_session_ids(
+        sender: &T::AccountId,
+        receiver: &T::AccountId,
+        start: u32,
+        count: u32,
+    ) -> Vec<u32> {
+        let end = start.saturating_add(count.min(MAX_SESSION_SCAN));
+        (start..end)
+            .filter(|id| Self::session_consumed(sender, receiver, *id))
+            .collect()
+    }
+
+    // whether session_id was claimed, invalidated or superseded by claim_aggregate
+    fn session_consumed(sender: &T::AccountId, receiver: &T::AccountId, session_id: u32) -> bool {
+        let key = (sender.clone(), receiver.clone());
+        session_id < SessionFloor::<T>::get(&key) || SessionId::<T>::contains_key(key, session_id)
+    }
+
+    // close the channel and emit ChannelClosed if it has expired, return whether it was closed
+    fn close_if_expired(sender: &T::AccountId, receiver: &T::AccountId) -> bool {
+        let chan = Channel::<T>::get((sender.clone(), receiver.clone()));
+        let time = T::Timestamp::now();
+        if chan.expiration < time {
+            Self::_close_channel(sender, receiver);
+            Self::deposit_event(RawEvent::ChannelClosed(
+                sender.clone(),
+                receiver.clone(),
+                time,
+                CloseKind::Expired,
+            ));
+            return true;
+        }
+        false
+    }
+
+    // pay amount out of the sender's deposit and mark session_id as consumed, without events
+    // if quiet
+    fn settle(
+        sender: &T::AccountId,
+        receiver: &T::AccountId,
+        session_id: u32,
+        amount: BalanceOf<T>,
+        quiet: bool,
+    ) -> DispatchResult {
+        // deposit, quota and limits are all in the settlement currency
+        let paid = T::RateProvider::to_settlement(amount);
+        Self::check_voucher_amount(sender, receiver, paid)?;
+        Self::pay_out(sender, receiver, amount, paid, quiet)?;
+        Self::log_claim(sender, receiver, Some(session_id), paid);
+        // mark session_id as used
+        SessionId::<T>::insert((sender.clone(), receiver.clone()), session_id, true);
+        Ok(())
+    }
+
+    // amount can be claimed from the channel in one go, by a voucher or from a stream
+    fn check_claim_amount(
+        sender: &T::AccountId,
+        receiver: &T::AccountId,
+        amount: BalanceOf<T>,
+    ) -> DispatchResult {
+        let chan = Channel::<T>::get((sender.clone(), receiver.clone()));
+        ensure!(
+            chan.state != ChannelState::Settled,
+            Error::<T>::ChannelSettled
+        );
+        ensure!(
+            !Self::in_grace_period(&chan),
+            Error::<T>::ClaimGracePeriodNotOver
+        );
+        Self::ensure_claim_rate(receiver)?;
+        // the refund in the settlement intent is final
+        ensure!(
+            !PendingSettlement::<T>::contains_key((sender.clone(), receiver.clone())),
+            Error::<T>::SettlementPending
+        );
+        // a zero claim would consume a session without paying anything
+        ensure!(!amount.is_zero(), Error::<T>::NothingToClaim);
+        let remaining = chan.deposit.saturating_sub(chan.claimed);
+        ensure!(amount <= remaining, "Not enough deposit left in channel");
+        let claimable = Self::claimable(&chan);
+        ensure!(amount <= claimable, Error::<T>::QuotaExceeded);
+        ensure!(
+            amount >= T::MinClaimAmount::get() || amount == claimable,
+            Error::<T>::ClaimTooSmall
+        );
+        Ok(())
+    }
+
+    // check_claim_amount for a voucher, which on a hybrid channel is also capped at max_per_claim
+    fn check_voucher_amount(
+        sender: &T::AccountId,
+        receiver: &T::AccountId,
+        amount: BalanceOf<T>,
+    ) -> DispatchResult {
+        Self::check_claim_amount(sender, receiver, amount)?;
+        if let Some(max) =
+            Streams::<T>::get((sender.clone(), receiver.clone())).and_then(|s| s.max_per_claim)
+        {
+            ensure!(amount <= max, Error::<T>::ClaimAboveMax);
+        }
+        Ok(())
+    }
+
+    // pay paid out of the sender's deposit for a claim worth signed and record it as claimed
+    fn pay_out(
+        sender: &T::AccountId,
+        receiver: &T::AccountId,
+        signed: BalanceOf<T>,
+        paid: BalanceOf<T>,
+        quiet: bool,
+    ) -> DispatchResult {
+        Self::pay_from_reserve(sender, receiver, paid)?;
+        Self::record_claim(sender, receiver, signed, paid, quiet);
+        Ok(())
+    }
+
+    // book amount as claimed from the channel once it has been paid; ClaimPayment reports the
+    // signed amount, everything else the amount that left the deposit. A quiet claim emits no
+    // event and is added to the QuietClaims summary instead
+    fn record_claim(
+        sender: &T::AccountId,
+        receiver: &T::AccountId,
+        signed: BalanceOf<T>,
+        amount: BalanceOf<T>,
+        quiet: bool,
+    ) {
+        Channel::<T>::mutate((sender.clone(), receiver.clone()), |c| {
+            c.claimed = c.claimed.saturating_add(amount);
+            if c.claimed >= c.deposit {
+                c.state = ChannelState::Settled;
+            }
+        });
+        if quiet {
+            TotalLocked::<T>::mutate(|t| *t = t.saturating_sub(amount));
+        } else {
+            Self::decrease_locked(amount);
+        }
+        Self::count_claim(receiver);
+        let seq = SettlementSeq::mutate(|seq| {
+            *seq = seq.saturating_add(1);
+            *seq
+        });
+        if quiet {
+            QuietClaims::<T>::mutate(|(count, total)| {
+                *count = count.saturating_add(1);
+                *total = total.saturating_add(amount);
+            });
+        } else {
+            Self::deposit_event(RawEvent::ClaimPayment(
+                sender.clone(),
+                receiver.clone(),
+                signed,
+                seq,
+            ));
+        }
+        T::OnClaim::on_claim(sender, receiver, amount);
+    }
+
+    // append a claim to the channel's ClaimLog, dropping the oldest entry past MaxClaimLog
+    fn log_claim(
+        sender: &T::AccountId,
+        receiver: &T::AccountId,
+        session_id: Option<u32>,
+        amount: BalanceOf<T>,
+    ) {
+        let max = T::MaxClaimLog::get();
+        if max == 0 {
+            return;
+        }
+        let key = (sender.clone(), receiver.clone());
+        let (mut first, next) = ClaimLogRange::<T>::get(&key);
+        let block = frame_system::Module::<T>::block_number();
+        ClaimLog::<T>::insert(&key, next, (session_id, amount, block));
+        if next - first >= max {
+            ClaimLog::<T>::remove(&key, first);
+            first += 1;
+        }
+        ClaimLogRange::<T>::insert(&key, (first, next + 1));
+    }
+
+    // logged claims of the channel from sender to receiver with a sequence number from start
+    // on, oldest first; at most count of them, capped at MaxClaimLog
+    pub fn claim_log(
+        sender: &T::AccountId,
+        receiver: &T::AccountId,
+        start: u32,
+        count: u32,
+    ) -> Vec<(u32, ClaimRecordOf<T>)> {
+        let key = (sender.clone(), receiver.clone());
+        let (first, next) = ClaimLogRange::<T>::get(&key);
+        let start = start.max(first);
+        let end = next.min(start.saturating_add(count.min(T::MaxClaimLog::get())));
+        (start..end)
+            .filter_map(|seq| ClaimLog::<T>::get(&key, seq).map(|claim| (seq, claim)))
+            .collect()
+    }
+
+    // move amount from the sender's reserved balance to the receiver's free balance; nothing
+    // moves unless all of it is reserved
+    fn pay_from_reserve(
+        sender: &T::AccountId,
+        receiver: &T::AccountId,
+        amount: BalanceOf<T>,
+    ) -> DispatchResult {
+        ensure!(
+            T::Currency::reserved_balance(sender) >= amount,
+            Error::<T>::InsufficientReserve
+        );
+        if !T::Currency::total_balance(receiver).is_zero() {
+            let leftover =
+                T::Currency::repatriate_reserved(sender, receiver, amount, BalanceStatus::Free)?;
+            ensure!(leftover.is_zero(), Error::<T>::InsufficientReserve);
+            return Ok(());
+        }
+        // repatriate_reserved can not create an account, so a first payment to a fresh
+        // receiver goes through the sender's free balance and has to be at least the
+        // existential deposit, otherwise the receiver would be reaped right away
+        ensure!(
+            amount >= T::Currency::minimum_balance(),
+            Error::<T>::ReceiverBelowExistentialDeposit
+        );
+        T::Currency::unreserve(sender, amount);
+        if let Err(e) = T::Currency::transfer(sender, receiver, amount, AllowDeath) {
+            // put the sender's deposit back as it was
+            let _ = T::Currency::reserve(sender, amount);
+            return Err(e);
+        }
+        Ok(())
+    }
+
     fn _close_channel(sender: &T::AccountId, receiver: &T::AccountId) {
+        // refund whatever the receiver did not claim: remaining = deposit - claimed
+        let chan = Channel::<T>::get((sender.clone(), receiver.clone()));
+        let remaining = chan.deposit.saturating_sub(chan.claimed);
+        T::Currency::unreserve(sender, remaining);
+        Self::decrease_locked(remaining);
         // remove all the sesson_ids of given channel
         SessionId::<T>::remove_prefix((sender.clone(), receiver.clone()));
+        SessionFloor::<T>::remove((sender.clone(), receiver.clone()));
+        PendingSettlement::<T>::remove((sender.clone(), receiver.clone()));
+        ClaimLog::<T>::remove_prefix((sender.clone(), receiver.clone()));
+        ClaimLogRange::<T>::remove((sender.clone(), receiver.clone()));
         Channel::<T>::remove((sender.clone(), receiver.clone()));
+        Streams::<T>::remove((sender.clone(), receiver.clone()));
+        Self::unqueue_expiry(sender, receiver, chan.expiration);
+        ReceiverCount::<T>::mutate_exists(sender, |count| {
+            *count = count.map(|c| c.saturating_sub(1)).filter(|c| *c > 0);
+        });
+        ChannelsOf::<T>::mutate_exists(sender, |receivers| {
+            if let Some(list) = receivers {
+                list.retain(|r| r != receiver);
+                if list.is_empty() {
+                    *receivers = None;
+                }
+            }
+        });
+    }
+
+    fn increase_locked(amount: BalanceOf<T>) {
+        let total = TotalLocked::<T>::mutate(|t| {
+            *t = t.saturating_add(amount);
+            *t
+        });
+        Self::deposit_event(RawEvent::TotalLockedChanged(total));
     }
 
-    // verify signature, signature is on hash of |receiver_addr|nonce|session_id|amount|
+    fn decrease_locked(amount: BalanceOf<T>) {
+        let total = TotalLocked::<T>::mutate(|t| {
+            *t = t.saturating_sub(amount);
+            *t
+        });
+        Self::deposit_event(RawEvent::TotalLockedChanged(total));
+    }
+
+    // verify signature, signature is on hash of the SCALE encoded Voucher
     // during one session_id, a sender can send multiple accumulated
     // micropayments with the same session_id; the receiver can only claim one payment of the same
     // session_id, i.e. the latest accumulated micropayment.
@@ -147,93 +1705,116 @@ impl<T: Trait> Module<T> {
         nonce: u64,
         session_id: u32,
         amount: BalanceOf<T>,
-        signature: &Vec<u8>,
+        valid_until: T::BlockNumber,
+        signature: &[u8],
     ) -> DispatchResult {
+        Self::ensure_voucher_live(valid_until)?;
+        let voucher = Self::voucher_of(sender, receiver, nonce, session_id, amount, valid_until);
+        Self::verify_sr25519(sender, &Self::voucher_hash(&voucher), signature)
+    }
+
+    fn voucher_of(
+        sender: &T::AccountId,
+        receiver: &T::AccountId,
+        nonce: u64,
+        session_id: u32,
+        amount: BalanceOf<T>,
+        valid_until: T::BlockNumber,
+    ) -> VoucherOf<T> {
+        VoucherOf::<T> {
+            sender: sender.clone(),
+            receiver: receiver.clone(),
+            nonce,
+            session_id,
+            amount,
+            valid_until,
+        }
+    }
+
+    // a voucher can be claimed up to and including its valid_until block
+    fn ensure_voucher_live(valid_until: T::BlockNumber) -> DispatchResult {
+        ensure!(
+            frame_system::Module::<T>::block_number() <= valid_until,
+            Error::<T>::VoucherExpired
+        );
+        Ok(())
+    }
+
+    // verify that signer's sr25519 key signed msg
+    fn verify_sr25519(signer: &T::AccountId, msg: &[u8; 32], signature: &[u8]) -> DispatchResult {
         let mut pk = [0u8; 32];
-        pk.copy_from_slice(&sender.encode());
+        pk.copy_from_slice(&signer.encode());
+        Self::verify_pubkey(pk, msg, signature)
+    }
+
+    // verify that the sr25519 key pk signed msg
+    fn verify_pubkey(pk: [u8; 32], msg: &[u8; 32], signature: &[u8]) -> DispatchResult {
         let pub_key = sr25519::Public::from_raw(pk);
 
+        ensure!(signature.len() == 64, Error::<T>::InvalidSignature);
         let mut sig = [0u8; 64];
-        sig.copy_from_slice(&signature);
+        sig.copy_from_slice(signature);
         let sig = sr25519::Signature::from_slice(&sig);
 
-        let msg = Self::construct_byte_array_and_hash(&receiver, nonce, session_id, amount);
-
-        let verified = sr25519_verify(&sig, &msg, &pub_key);
+        let verified = sr25519_verify(&sig, msg, &pub_key);
         ensure!(verified, "Fail to verify signature");
 
         Ok(())
     }
 
-    // construct data from |receiver_addr|session_id|amount| and hash it
-    fn construct_byte_array_and_hash(
-        address: &T::AccountId,
+    // verify that the secp256k1 key pubkey signed msg; as with sp_core's ecdsa::Pair::sign
+    // the signature is on the blake2_256 hash of msg
+    fn verify_ecdsa(
+        pubkey: &[u8; 33],
+        msg: &[u8; 32],
+        signature: &ecdsa::Signature,
+    ) -> DispatchResult {
+        let pub_key = ecdsa::Public::from_raw(*pubkey);
+        ensure!(
+            ecdsa_verify(signature, msg, &pub_key),
+            "Fail to verify signature"
+        );
+        Ok(())
+    }
+
+    // the current receiver signs on hash of |"reassign"|sender_addr|new_receiver_addr|nonce|
+    // to hand its channel over; nonce is the one of the channel being handed over
+    fn construct_reassign_hash(
+        sender: &T::AccountId,
+        new_receiver: &T::AccountId,
         nonce: u64,
-        session_id: u32,
-        amount: BalanceOf<T>,
     ) -> [u8; 32] {
         let mut data = Vec::new();
-        data.extend_from_slice(&address.encode());
+        data.extend_from_slice(b"reassign");
+        data.extend_from_slice(&sender.encode());
+        data.extend_from_slice(&new_receiver.encode());
         data.extend_from_slice(&nonce.to_be_bytes());
-        data.extend_from_slice(&session_id.to_be_bytes());
-        data.extend_from_slice(&amount.encode());
-        let hash = sp_io::hashing::blake2_256(&data);
-        hash
+        sp_io::hashing::blake2_256(&data)
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[test]
-    fn test_blake2_hash() {
-        let bob: [u8; 32] = [
-            142, 175, 4, 21, 22, 135, 115, 99, 38, 201, 254, 161, 126, 37, 252, 82, 135, 97, 54,
-            147, 201, 18, 144, 156, 178, 38, 170, 71, 148, 242, 106, 72,
-        ];
-        let session_id: u32 = 22;
-        let nonce: u64 = 5;
-        let amount: u128 = 100;
+    // the counterparty of net_settle signs on hash of
+    // |"net-settle"|caller_addr|counterparty_addr|to_counterparty|from_counterparty| where both
+    // vouchers are the SCALE encoded (nonce, session_id, amount) of their channel
+    fn construct_net_settle_hash(
+        caller: &T::AccountId,
+        counterparty: &T::AccountId,
+        to_counterparty: (u64, u32, BalanceOf<T>),
+        from_counterparty: (u64, u32, BalanceOf<T>),
+    ) -> [u8; 32] {
         let mut data = Vec::new();
+        data.extend_from_slice(b"net-settle");
+        data.extend_from_slice(&caller.encode());
+        data.extend_from_slice(&counterparty.encode());
+        to_counterparty.encode_to(&mut data);
+        from_counterparty.encode_to(&mut data);
+        sp_io::hashing::blake2_256(&data)
+    }
 
-        let should_be: [u8; 32] = [
-            204, 32, 30, 136, 139, 38, 43, 64, 99, 194, 191, 149, 97, 108, 87, 173, 224, 25, 104,
-            100, 0, 179, 72, 91, 202, 84, 34, 190, 178, 119, 59, 41,
-        ];
-
-        data.extend_from_slice(&bob);
-        data.extend_from_slice(&session_id.to_be_bytes());
-        data.extend_from_slice(&amount.to_be_bytes());
-        let hash = sp_io::hashing::blake2_256(&data);
-        assert_eq!(&hash, &should_be);
-    }
-
-    #[test]
-    fn test_signature() {
-        let sig: [u8; 64] = [
-            68, 47, 70, 69, 17, 14, 9, 253, 233, 25, 253, 31, 54, 87, 196, 88, 192, 81, 241, 235,
-            51, 175, 232, 189, 181, 176, 89, 123, 223, 237, 162, 39, 79, 234, 237, 116, 157, 88,
-            19, 64, 224, 90, 66, 80, 4, 202, 207, 153, 220, 159, 142, 118, 210, 8, 25, 102, 159,
-            44, 229, 1, 58, 237, 243, 135,
-        ];
-        assert_eq!(sig.len(), 64);
-        let pk: [u8; 32] = [
-            212, 53, 147, 199, 21, 253, 211, 28, 97, 20, 26, 189, 4, 169, 159, 214, 130, 44, 133,
-            88, 133, 76, 205, 227, 154, 86, 132, 231, 165, 109, 162, 125,
-        ];
-        assert_eq!(pk.len(), 32);
-        let msg: [u8; 32] = [
-            204, 32, 30, 136, 139, 38, 43, 64, 99, 194, 191, 149, 97, 108, 87, 173, 224, 25, 104,
-            100, 0, 179, 72, 91, 202, 84, 34, 190, 178, 119, 59, 41,
-        ];
-
-        let pk = sr25519::Public::from_raw(pk);
-        let sig = sr25519::Signature::from_slice(&sig);
-        println!("pk:{:?}", pk);
-        println!("sig:{:?}", sig);
-        println!("msg:{:?}", msg);
-        let verified = sr25519_verify(&sig, &msg, &pk);
-        assert_eq!(verified, true);
+    // hash of VOUCHER_DOMAIN followed by the SCALE encoding of voucher, this is what the
+    // sender signs
+    fn voucher_hash(voucher: &VoucherOf<T>) -> [u8; 32] {
+        let mut data = VOUCHER_DOMAIN.to_vec();
+        voucher.encode_to(&mut data);
+        sp_io::hashing::blake2_256(&data)
     }
 }
@@ -0,0 +1,345 @@
+use crate::{Module, OnChannelOpened, OnClaim, RateProvider, SameEntity, Trait};
+use frame_support::{
+    impl_outer_event, impl_outer_origin, parameter_types,
+    traits::{EnsureOrigin, Get},
+    weights::Weight,
+};
+use frame_system as system;
+use sp_core::{ecdsa, sr25519, Pair, H256};
+use sp_runtime::{
+    testing::Header,
+    traits::{BlakeTwo256, Convert, IdentityLookup},
+    AccountId32, Perbill,
+};
+use std::{cell::RefCell, collections::BTreeMap};
+
+impl_outer_origin! {
+    pub enum Origin for Test {}
+}
+
+mod micropayment {
+    pub use crate::Event;
+}
+
+impl_outer_event! {
+    pub enum TestEvent for Test {
+        system<T>,
+        pallet_balances<T>,
+        micropayment<T>,
+    }
+}
+
+// Configure a mock runtime to test the pallet.
+
+#[derive(Clone, Eq, PartialEq)]
+pub struct Test;
+parameter_types! {
+    pub const BlockHashCount: u64 = 250;
+    pub const MaximumBlockWeight: Weight = 1024;
+    pub const MaximumBlockLength: u32 = 2 * 1024;
+    pub const AvailableBlockRatio: Perbill = Perbill::from_percent(75);
+}
+
+impl system::Trait for Test {
+    type BaseCallFilter = ();
+    type Origin = Origin;
+    type Call = ();
+    type Index = u64;
+    type BlockNumber = u64;
+    type Hash = H256;
+    type Hashing = BlakeTwo256;
+    type AccountId = AccountId32;
+    type Lookup = IdentityLookup<Self::AccountId>;
+    type Header = Header;
+    type Event = TestEvent;
+    type BlockHashCount = BlockHashCount;
+    type MaximumBlockWeight = MaximumBlockWeight;
+    type DbWeight = ();
+    type BlockExecutionWeight = ();
+    type ExtrinsicBaseWeight = ();
+    type MaximumExtrinsicWeight = MaximumBlockWeight;
+    type MaximumBlockLength = MaximumBlockLength;
+    type AvailableBlockRatio = AvailableBlockRatio;
+    type Version = ();
+    type ModuleToIndex = ();
+    type AccountData = pallet_balances::AccountData<u64>;
+    type OnNewAccount = ();
+    type OnKilledAccount = ();
+    type SystemWeightInfo = ();
+}
+
+parameter_types! {
+    pub const ExistentialDeposit: u64 = 5;
+    pub const MinimumPeriod: u64 = 5;
+    pub const MaxSignatureLen: u32 = 64;
+    pub const MinClaimAmount: u64 = 10;
+    pub const MaxCloseBatch: u32 = 2;
+    pub const ChallengePeriod: u64 = 10_000;
+    pub const MaxExpiryWarnings: u32 = 2;
+    pub const MaxClaimLog: u32 = 3;
+    pub const SettlementWindow: u64 = 5;
+}
+
+impl pallet_balances::Trait for Test {
+    type Balance = u64;
+    type Event = TestEvent;
+    type DustRemoval = ();
+    type ExistentialDeposit = ExistentialDeposit;
+    type AccountStore = System;
+    type WeightInfo = ();
+}
+
+impl pallet_timestamp::Trait for Test {
+    type Moment = u64;
+    type OnTimestampSet = ();
+    type MinimumPeriod = MinimumPeriod;
+    type WeightInfo = ();
+}
+
+// Signed origins open channels for themselves, root opens on behalf of charlie.
+pub struct EnsureSignedOrSponsored;
+impl EnsureOrigin<Origin> for EnsureSignedOrSponsored {
+    type Success = AccountId32;
+    fn try_origin(o: Origin) -> Result<Self::Success, Origin> {
+        o.into().and_then(|o| match o {
+            system::RawOrigin::Signed(who) => Ok(who),
+            system::RawOrigin::Root => Ok(charlie()),
+            r => Err(Origin::from(r)),
+        })
+    }
+
+    #[cfg(feature = "runtime-benchmarks")]
+    fn successful_origin() -> Origin {
+        Origin::root()
+    }
+}
+
+// the account of a secp256k1 key is the blake2_256 hash of the compressed key, as for
+// MultiSigner::Ecdsa
+pub struct AccountFromEcdsa;
+impl Convert<[u8; 33], AccountId32> for AccountFromEcdsa {
+    fn convert(pubkey: [u8; 33]) -> AccountId32 {
+        AccountId32::from(sp_io::hashing::blake2_256(&pubkey))
+    }
+}
+
+thread_local! {
+    pub static OPENED: RefCell<Vec<(AccountId32, AccountId32, u64)>> = RefCell::new(vec![]);
+}
+
+// records every channel opened, so tests can check the hook was called
+pub struct RecordOpened;
+impl OnChannelOpened<AccountId32, u64> for RecordOpened {
+    fn on_channel_opened(sender: &AccountId32, receiver: &AccountId32, deposit: u64) {
+        OPENED.with(|o| {
+            o.borrow_mut()
+                .push((sender.clone(), receiver.clone(), deposit))
+        });
+    }
+}
+
+pub fn opened_channels() -> Vec<(AccountId32, AccountId32, u64)> {
+    OPENED.with(|o| o.borrow().clone())
+}
+
+thread_local! {
+    pub static SETTLED: RefCell<BTreeMap<AccountId32, u64>> = RefCell::new(BTreeMap::new());
+}
+
+// adds up what every receiver has been paid
+pub struct TotalSettled;
+impl OnClaim<AccountId32, u64> for TotalSettled {
+    fn on_claim(_sender: &AccountId32, receiver: &AccountId32, amount: u64) {
+        SETTLED.with(|s| *s.borrow_mut().entry(receiver.clone()).or_default() += amount);
+    }
+}
+
+pub fn settled_to(receiver: &AccountId32) -> u64 {
+    SETTLED.with(|s| s.borrow().get(receiver).copied().unwrap_or_default())
+}
+
+thread_local! {
+    pub static RATE: RefCell<u64> = RefCell::new(1);
+}
+
+// settlement units paid per signed unit, 1 unless a test changes it
+pub struct FixedRate;
+impl RateProvider<u64> for FixedRate {
+    fn to_settlement(amount: u64) -> u64 {
+        RATE.with(|r| amount * *r.borrow())
+    }
+}
+
+pub fn set_rate(rate: u64) {
+    RATE.with(|r| *r.borrow_mut() = rate);
+}
+
+thread_local! {
+    pub static GRACE: RefCell<u64> = RefCell::new(0);
+}
+
+// no grace period unless a test sets one
+pub struct ClaimGracePeriod;
+impl Get<u64> for ClaimGracePeriod {
+    fn get() -> u64 {
+        GRACE.with(|g| *g.borrow())
+    }
+}
+
+pub fn set_grace_period(period: u64) {
+    GRACE.with(|g| *g.borrow_mut() = period);
+}
+
+thread_local! {
+    pub static CLAIM_RATE: RefCell<(u32, u64)> = RefCell::new((0, 0));
+}
+
+// no receiver-wide limit unless a test sets one
+pub struct GlobalClaimRate;
+impl Get<(u32, u64)> for GlobalClaimRate {
+    fn get() -> (u32, u64) {
+        CLAIM_RATE.with(|r| *r.borrow())
+    }
+}
+
+pub fn set_claim_rate(claims: u32, window: u64) {
+    CLAIM_RATE.with(|r| *r.borrow_mut() = (claims, window));
+}
+
+thread_local! {
+    pub static REQUIRE_SIGNATURE: RefCell<bool> = RefCell::new(true);
+}
+
+// signatures are checked unless a test turns it off
+pub struct RequireSignature;
+impl Get<bool> for RequireSignature {
+    fn get() -> bool {
+        REQUIRE_SIGNATURE.with(|r| *r.borrow())
+    }
+}
+
+pub fn set_require_signature(required: bool) {
+    REQUIRE_SIGNATURE.with(|r| *r.borrow_mut() = required);
+}
+
+thread_local! {
+    pub static WARNING_WINDOW: RefCell<u64> = RefCell::new(10_000);
+}
+
+// channels are announced 10 seconds before they expire unless a test changes it
+pub struct WarningWindow;
+impl Get<u64> for WarningWindow {
+    fn get() -> u64 {
+        WARNING_WINDOW.with(|w| *w.borrow())
+    }
+}
+
+pub fn set_warning_window(window: u64) {
+    WARNING_WINDOW.with(|w| *w.borrow_mut() = window);
+}
+
+// dave is a proxy of charlie
+pub struct ProxyOfCharlie;
+impl SameEntity<AccountId32> for ProxyOfCharlie {
+    fn is_same_entity(a: &AccountId32, b: &AccountId32) -> bool {
+        let (c, d) = (charlie(), dave());
+        (*a == c && *b == d) || (*a == d && *b == c)
+    }
+}
+
+impl Trait for Test {
+    type Event = TestEvent;
+    type Currency = Balances;
+    type Timestamp = Timestamp;
+    type OpenOrigin = EnsureSignedOrSponsored;
+    type MaxSignatureLen = MaxSignatureLen;
+    type MinClaimAmount = MinClaimAmount;
+    type AccountFromPubkey = AccountFromEcdsa;
+    type MaxCloseBatch = MaxCloseBatch;
+    type OnChannelOpened = RecordOpened;
+    type SameEntityCheck = ProxyOfCharlie;
+    type ChallengePeriod = ChallengePeriod;
+    type OnClaim = TotalSettled;
+    type ForceOrigin = system::EnsureRoot<AccountId32>;
+    type WarningWindow = WarningWindow;
+    type MaxExpiryWarnings = MaxExpiryWarnings;
+    type RateProvider = FixedRate;
+    type ClaimGracePeriod = ClaimGracePeriod;
+    type MaxClaimLog = MaxClaimLog;
+    type GlobalClaimRate = GlobalClaimRate;
+    type RequireSignature = RequireSignature;
+    type SettlementWindow = SettlementWindow;
+}
+
+pub type System = system::Module<Test>;
+pub type Balances = pallet_balances::Module<Test>;
+pub type Timestamp = pallet_timestamp::Module<Test>;
+pub type Micropayment = Module<Test>;
+
+// Accounts are sr25519 public keys so that vouchers can be signed in tests.
+pub fn alice_pair() -> sr25519::Pair {
+    sr25519::Pair::from_seed(&[1u8; 32])
+}
+
+pub fn bob_pair() -> sr25519::Pair {
+    sr25519::Pair::from_seed(&[2u8; 32])
+}
+
+pub fn charlie_pair() -> sr25519::Pair {
+    sr25519::Pair::from_seed(&[3u8; 32])
+}
+
+// dave has no balance at genesis
+pub fn dave_pair() -> sr25519::Pair {
+    sr25519::Pair::from_seed(&[4u8; 32])
+}
+
+pub fn account_of(pair: &sr25519::Pair) -> AccountId32 {
+    AccountId32::from(pair.public().0)
+}
+
+// a sender signing with a secp256k1 key, it has no balance at genesis
+pub fn eve_pair() -> ecdsa::Pair {
+    ecdsa::Pair::from_seed(&[5u8; 32])
+}
+
+pub fn ecdsa_pubkey(pair: &ecdsa::Pair) -> [u8; 33] {
+    let mut pubkey = [0u8; 33];
+    pubkey.copy_from_slice(pair.public().as_ref());
+    pubkey
+}
+
+pub fn eve() -> AccountId32 {
+    AccountFromEcdsa::convert(ecdsa_pubkey(&eve_pair()))
+}
+
+pub fn alice() -> AccountId32 {
+    account_of(&alice_pair())
+}
+
+pub fn bob() -> AccountId32 {
+    account_of(&bob_pair())
+}
+
+pub fn charlie() -> AccountId32 {
+    account_of(&charlie_pair())
+}
+
+pub fn dave() -> AccountId32 {
+    account_of(&dave_pair())
+}
+
+// Build genesis storage according to the mock runtime.
+pub fn new_test_ext() -> sp_io::TestExternalities {
+    let mut t = system::GenesisConfig::default()
+        .build_storage::<Test>()
+        .unwrap();
+    pallet_balances::GenesisConfig::<Test> {
+        balances: vec![(alice(), 1_000), (bob(), 1_000), (charlie(), 1_000)],
+    }
+    .assimilate_storage(&mut t)
+    .unwrap();
+    let mut ext = sp_io::TestExternalities::new(t);
+    ext.execute_with(|| System::set_block_number(1));
+    ext
+}
@@ -0,0 +1,3657 @@
+use super::*;
+use crate::mock::*;
+use frame_support::{
+    assert_noop, assert_ok,
+    storage::{IterableStorageDoubleMap, IterableStorageMap, StorageMap},
+    traits::{Currency, OnFinalize, OnInitialize},
+};
+use sp_core::Pair;
+use sp_runtime::{AccountId32, DispatchError};
+
+fn micropayment_events() -> Vec<Event<Test>> {
+    System::events()
+        .into_iter()
+        .filter_map(|record| match record.event {
+            TestEvent::micropayment(event) => Some(event),
+            _ => None,
+        })
+        .collect()
+}
+
+// valid_until for vouchers that never expire
+const NO_EXPIRY: u64 = u64::MAX;
+
+fn sign_voucher(
+    pair: &sr25519::Pair,
+    receiver: &AccountId32,
+    nonce: u64,
+    session_id: u32,
+    amount: u64,
+) -> Vec<u8> {
+    sign_voucher_until(pair, receiver, nonce, session_id, amount, NO_EXPIRY)
+}
+
+fn sign_voucher_until(
+    pair: &sr25519::Pair,
+    receiver: &AccountId32,
+    nonce: u64,
+    session_id: u32,
+    amount: u64,
+    valid_until: u64,
+) -> Vec<u8> {
+    let voucher = Voucher {
+        sender: account_of(pair),
+        receiver: receiver.clone(),
+        nonce,
+        session_id,
+        amount,
+        valid_until,
+    };
+    pair.sign(&Micropayment::voucher_hash(&voucher)).0.to_vec()
+}
+
+fn sr(sig: Vec<u8>) -> TypedSignature {
+    TypedSignature::Sr25519(sr25519::Signature::from_slice(&sig))
+}
+
+// voucher from eve, who signs with a secp256k1 key
+fn sign_voucher_ecdsa(
+    pair: &ecdsa::Pair,
+    receiver: &AccountId32,
+    nonce: u64,
+    session_id: u32,
+    amount: u64,
+) -> TypedSignature {
+    let voucher = Voucher {
+        sender: eve(),
+        receiver: receiver.clone(),
+        nonce,
+        session_id,
+        amount,
+        valid_until: NO_EXPIRY,
+    };
+    TypedSignature::Ecdsa(pair.sign(&Micropayment::voucher_hash(&voucher)))
+}
+
+#[test]
+fn test_blake2_hash() {
+    let bob: [u8; 32] = [
+        142, 175, 4, 21, 22, 135, 115, 99, 38, 201, 254, 161, 126, 37, 252, 82, 135, 97, 54, 147,
+        201, 18, 144, 156, 178, 38, 170, 71, 148, 242, 106, 72,
+    ];
+    let session_id: u32 = 22;
+    let nonce: u64 = 5;
+    let amount: u128 = 100;
+    let mut data = Vec::new();
+
+    let should_be: [u8; 32] = [
+        204, 32, 30, 136, 139, 38, 43, 64, 99, 194, 191, 149, 97, 108, 87, 173, 224, 25, 104, 100,
+        0, 179, 72, 91, 202, 84, 34, 190, 178, 119, 59, 41,
+    ];
+
+    data.extend_from_slice(&bob);
+    data.extend_from_slice(&session_id.to_be_bytes());
+    data.extend_from_slice(&amount.to_be_bytes());
+    let hash = sp_io::hashing::blake2_256(&data);
+    assert_eq!(&hash, &should_be);
+}
+
+#[test]
+fn test_signature() {
+    let sig: [u8; 64] = [
+        68, 47, 70, 69, 17, 14, 9, 253, 233, 25, 253, 31, 54, 87, 196, 88, 192, 81, 241, 235, 51,
+        175, 232, 189, 181, 176, 89, 123, 223, 237, 162, 39, 79, 234, 237, 116, 157, 88, 19, 64,
+        224, 90, 66, 80, 4, 202, 207, 153, 220, 159, 142, 118, 210, 8, 25, 102, 159, 44, 229, 1,
+        58, 237, 243, 135,
+    ];
+    assert_eq!(sig.len(), 64);
+    let pk: [u8; 32] = [
+        212, 53, 147, 199, 21, 253, 211, 28, 97, 20, 26, 189, 4, 169, 159, 214, 130, 44, 133, 88,
+        133, 76, 205, 227, 154, 86, 132, 231, 165, 109, 162, 125,
+    ];
+    assert_eq!(pk.len(), 32);
+    let msg: [u8; 32] = [
+        204, 32, 30, 136, 139, 38, 43, 64, 99, 194, 191, 149, 97, 108, 87, 173, 224, 25, 104, 100,
+        0, 179, 72, 91, 202, 84, 34, 190, 178, 119, 59, 41,
+    ];
+
+    let pk = sr25519::Public::from_raw(pk);
+    let sig = sr25519::Signature::from_slice(&sig);
+    println!("pk:{:?}", pk);
+    println!("sig:{:?}", sig);
+    println!("msg:{:?}", msg);
+    let verified = sr25519_verify(&sig, &msg, &pk);
+    assert_eq!(verified, true);
+}
+
+#[test]
+fn open_channel_exposes_channel_fields() {
+    new_test_ext().execute_with(|| {
+        Timestamp::set_timestamp(1_000);
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            bob(),
+            300,
+            60
+        ));
+        let chan = Micropayment::get_channel((alice(), bob()));
+        assert_eq!(chan.sender, alice());
+        assert_eq!(chan.receiver, bob());
+        assert_eq!(chan.nonce, 0);
+        assert_eq!(chan.opened, 1_000);
+        assert_eq!(chan.opened_at, 1);
+        assert_eq!(chan.expiration, 61_000);
+        assert_eq!(chan.deposit, 300);
+        assert_eq!(chan.claimed, 0);
+        assert_eq!(chan.state, ChannelState::Open);
+        assert_eq!(chan.quota, None);
+    });
+}
+
+#[test]
+fn open_channel_reserves_deposit() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            bob(),
+            300,
+            60
+        ));
+        assert_eq!(Balances::free_balance(alice()), 700);
+        assert_eq!(Balances::reserved_balance(alice()), 300);
+    });
+}
+
+#[test]
+fn claim_payment_is_paid_from_deposit() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            bob(),
+            300,
+            60
+        ));
+        let sig = sign_voucher(&alice_pair(), &bob(), 0, 1, 120);
+        assert_ok!(Micropayment::claim_payment(
+            Origin::signed(bob()),
+            alice(),
+            1,
+            120,
+            NO_EXPIRY,
+            sr(sig)
+        ));
+        assert_eq!(Balances::free_balance(alice()), 700);
+        assert_eq!(Balances::reserved_balance(alice()), 180);
+        assert_eq!(Balances::free_balance(bob()), 1_120);
+        assert_eq!(Micropayment::get_channel((alice(), bob())).claimed, 120);
+    });
+}
+
+#[test]
+fn claim_payment_cannot_exceed_deposit() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            bob(),
+            100,
+            60
+        ));
+        let sig = sign_voucher(&alice_pair(), &bob(), 0, 1, 101);
+        assert_noop!(
+            Micropayment::claim_payment(Origin::signed(bob()), alice(), 1, 101, NO_EXPIRY, sr(sig)),
+            "Not enough deposit left in channel"
+        );
+    });
+}
+
+#[test]
+fn close_channel_refunds_remaining_deposit() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            bob(),
+            300,
+            60
+        ));
+        let sig = sign_voucher(&alice_pair(), &bob(), 0, 1, 50);
+        assert_ok!(Micropayment::claim_payment(
+            Origin::signed(bob()),
+            alice(),
+            1,
+            50,
+            NO_EXPIRY,
+            sr(sig)
+        ));
+        let sig = sign_voucher(&alice_pair(), &bob(), 0, 2, 70);
+        assert_ok!(Micropayment::claim_payment(
+            Origin::signed(bob()),
+            alice(),
+            2,
+            70,
+            NO_EXPIRY,
+            sr(sig)
+        ));
+        let free_before = Balances::free_balance(alice());
+        assert_ok!(Micropayment::close_channel(
+            Origin::signed(bob()),
+            alice(),
+            false
+        ));
+        // remaining = 300 - (50 + 70)
+        assert_eq!(Balances::free_balance(alice()), free_before + 180);
+        assert_eq!(Balances::reserved_balance(alice()), 0);
+        assert!(!Channel::<Test>::contains_key((alice(), bob())));
+    });
+}
+
+#[test]
+fn settle_and_close_claims_then_refunds() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            bob(),
+            300,
+            60
+        ));
+        let sig = sign_voucher(&alice_pair(), &bob(), 0, 1, 50);
+        assert_ok!(Micropayment::claim_payment(
+            Origin::signed(bob()),
+            alice(),
+            1,
+            50,
+            NO_EXPIRY,
+            sr(sig)
+        ));
+        let sig = sign_voucher(&alice_pair(), &bob(), 0, 2, 70);
+        assert_ok!(Micropayment::settle_and_close(
+            Origin::signed(bob()),
+            alice(),
+            2,
+            70,
+            NO_EXPIRY,
+            sr(sig)
+        ));
+        assert_eq!(Balances::free_balance(bob()), 1_120);
+        // remaining = 300 - (50 + 70)
+        assert_eq!(Balances::free_balance(alice()), 880);
+        assert_eq!(Balances::reserved_balance(alice()), 0);
+        assert!(!Channel::<Test>::contains_key((alice(), bob())));
+        assert!(!Micropayment::get_session_id((alice(), bob()), 1));
+        assert!(!Micropayment::get_session_id((alice(), bob()), 2));
+        let events = micropayment_events();
+        assert!(events.contains(&RawEvent::ClaimPayment(alice(), bob(), 70, 2)));
+        assert_eq!(
+            events.last(),
+            Some(&RawEvent::ChannelClosed(
+                alice(),
+                bob(),
+                0,
+                CloseKind::Cooperative
+            ))
+        );
+    });
+}
+
+#[test]
+fn settle_and_close_keeps_channel_on_bad_voucher() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            bob(),
+            300,
+            60
+        ));
+        let sig = sign_voucher(&alice_pair(), &bob(), 0, 1, 50);
+        assert_noop!(
+            Micropayment::settle_and_close(
+                Origin::signed(bob()),
+                alice(),
+                1,
+                70,
+                NO_EXPIRY,
+                sr(sig.clone())
+            ),
+            "Fail to verify signature"
+        );
+        // an already consumed session is not settled again either
+        assert_ok!(Micropayment::claim_payment(
+            Origin::signed(bob()),
+            alice(),
+            1,
+            50,
+            NO_EXPIRY,
+            sr(sig.clone())
+        ));
+        assert_noop!(
+            Micropayment::settle_and_close(
+                Origin::signed(bob()),
+                alice(),
+                1,
+                50,
+                NO_EXPIRY,
+                sr(sig)
+            ),
+            "SessionID already consumed"
+        );
+        assert!(Channel::<Test>::contains_key((alice(), bob())));
+        assert_eq!(Balances::reserved_balance(alice()), 250);
+    });
+}
+
+#[test]
+fn deferred_close_moves_funds_on_finalize() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            bob(),
+            300,
+            60
+        ));
+        let sig = sign_voucher(&alice_pair(), &bob(), 0, 1, 50);
+        assert_ok!(Micropayment::claim_payment(
+            Origin::signed(bob()),
+            alice(),
+            1,
+            50,
+            NO_EXPIRY,
+            sr(sig)
+        ));
+        assert_noop!(
+            Micropayment::finalize_settlement(Origin::signed(charlie()), alice(), bob()),
+            Error::<Test>::NoPendingSettlement
+        );
+
+        // the intent is recorded, but the deposit stays reserved
+        System::set_block_number(3);
+        assert_ok!(Micropayment::close_channel(
+            Origin::signed(bob()),
+            alice(),
+            true
+        ));
+        assert_eq!(Micropayment::pending_settlement((alice(), bob())), Some(3));
+        assert_eq!(
+            micropayment_events().last(),
+            Some(&RawEvent::SettlementIntent(alice(), bob(), 250))
+        );
+        assert_eq!(Balances::reserved_balance(alice()), 250);
+        assert!(Channel::<Test>::contains_key((alice(), bob())));
+
+        // nothing more can be claimed and the close can not be asked for twice
+        let sig = sign_voucher(&alice_pair(), &bob(), 0, 2, 50);
+        assert_noop!(
+            Micropayment::claim_payment(Origin::signed(bob()), alice(), 2, 50, NO_EXPIRY, sr(sig)),
+            Error::<Test>::SettlementPending
+        );
+        assert_noop!(
+            Micropayment::close_channel(Origin::signed(bob()), alice(), false),
+            Error::<Test>::SettlementPending
+        );
+
+        // funds move once SettlementWindow, 5 blocks in the mock, has passed
+        System::set_block_number(7);
+        assert_noop!(
+            Micropayment::finalize_settlement(Origin::signed(charlie()), alice(), bob()),
+            Error::<Test>::SettlementWindowNotOver
+        );
+        System::set_block_number(8);
+        let free_before = Balances::free_balance(alice());
+        assert_ok!(Micropayment::finalize_settlement(
+            Origin::signed(charlie()),
+            alice(),
+            bob()
+        ));
+        assert_eq!(Balances::free_balance(alice()), free_before + 250);
+        assert_eq!(Balances::reserved_balance(alice()), 0);
+        assert!(!Channel::<Test>::contains_key((alice(), bob())));
+        assert_eq!(Micropayment::pending_settlement((alice(), bob())), None);
+        assert_eq!(
+            micropayment_events().last(),
+            Some(&RawEvent::ChannelClosed(
+                alice(),
+                bob(),
+                0,
+                CloseKind::Cooperative
+            ))
+        );
+    });
+}
+
+#[test]
+fn deferred_close_stops_stream_claims() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(Micropayment::open_stream_channel(
+            Origin::signed(alice()),
+            bob(),
+            300,
+            60,
+            10
+        ));
+        System::set_block_number(6);
+        assert_ok!(Micropayment::close_channel(
+            Origin::signed(bob()),
+            alice(),
+            true
+        ));
+        assert_eq!(
+            micropayment_events().last(),
+            Some(&RawEvent::SettlementIntent(alice(), bob(), 300))
+        );
+        System::set_block_number(9);
+        assert_noop!(
+            Micropayment::claim_stream(Origin::signed(bob()), alice()),
+            Error::<Test>::SettlementPending
+        );
+        System::set_block_number(11);
+        assert_ok!(Micropayment::finalize_settlement(
+            Origin::signed(bob()),
+            alice(),
+            bob()
+        ));
+        assert_eq!(Balances::reserved_balance(alice()), 0);
+        assert_eq!(Balances::free_balance(alice()), 1_000);
+    });
+}
+
+#[test]
+fn expired_channel_refunds_on_claim() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            bob(),
+            300,
+            60
+        ));
+        Timestamp::set_timestamp(61_000);
+        let sig = sign_voucher(&alice_pair(), &bob(), 0, 1, 50);
+        assert_ok!(Micropayment::claim_payment(
+            Origin::signed(bob()),
+            alice(),
+            1,
+            50,
+            NO_EXPIRY,
+            sr(sig)
+        ));
+        assert_eq!(Balances::free_balance(alice()), 1_000);
+        assert_eq!(Balances::free_balance(bob()), 1_000);
+        assert!(!Channel::<Test>::contains_key((alice(), bob())));
+    });
+}
+
+#[test]
+fn oversized_signature_is_rejected_before_lookup() {
+    new_test_ext().execute_with(|| {
+        // no channel exists, the length check alone must reject the call
+        assert_noop!(
+            Micropayment::settle_voucher(&alice(), &bob(), 1, 10, NO_EXPIRY, &[0u8; 100_000]),
+            Error::<Test>::SignatureTooLong
+        );
+    });
+}
+
+#[test]
+fn short_signature_is_rejected() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            bob(),
+            300,
+            60
+        ));
+        assert_noop!(
+            Micropayment::settle_voucher(&alice(), &bob(), 1, 10, NO_EXPIRY, &[0u8; 32]),
+            Error::<Test>::InvalidSignature
+        );
+    });
+}
+
+#[test]
+fn voucher_signature_needs_domain_tag() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            bob(),
+            300,
+            60
+        ));
+        let voucher = Voucher {
+            sender: alice(),
+            receiver: bob(),
+            nonce: 0,
+            session_id: 1,
+            amount: 10,
+            valid_until: NO_EXPIRY,
+        };
+        let undomained = voucher.using_encoded(sp_io::hashing::blake2_256);
+        let sig = alice_pair().sign(&undomained).0.to_vec();
+        assert_noop!(
+            Micropayment::claim_payment(Origin::signed(bob()), alice(), 1, 10, NO_EXPIRY, sr(sig)),
+            "Fail to verify signature"
+        );
+
+        let mut data = b"e2-micropay-v1".to_vec();
+        data.extend_from_slice(&voucher.encode());
+        let sig = alice_pair()
+            .sign(&sp_io::hashing::blake2_256(&data))
+            .0
+            .to_vec();
+        assert_ok!(Micropayment::claim_payment(
+            Origin::signed(bob()),
+            alice(),
+            1,
+            10,
+            NO_EXPIRY,
+            sr(sig)
+        ));
+    });
+}
+
+#[test]
+fn typed_signature_fixes_length_at_decode() {
+    let sig = sign_voucher(&alice_pair(), &bob(), 0, 1, 10);
+    let typed = sr(sig.clone());
+    assert_eq!(
+        TypedSignature::decode(&mut &typed.encode()[..]).ok(),
+        Some(typed)
+    );
+
+    // an sr25519 signature cut short, or passed off as ecdsa, does not decode
+    let mut short = vec![1u8];
+    short.extend_from_slice(&sig[..32]);
+    assert!(TypedSignature::decode(&mut &short[..]).is_err());
+    let mut as_ecdsa = vec![0u8];
+    as_ecdsa.extend_from_slice(&sig);
+    assert!(TypedSignature::decode(&mut &as_ecdsa[..]).is_err());
+}
+
+#[test]
+fn sr25519_typed_signature_is_claimed() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            bob(),
+            300,
+            60
+        ));
+        let sig = sign_voucher(&alice_pair(), &bob(), 0, 1, 10);
+        assert_ok!(Micropayment::claim_payment(
+            Origin::signed(bob()),
+            alice(),
+            1,
+            10,
+            NO_EXPIRY,
+            TypedSignature::Sr25519(sr25519::Signature::from_slice(&sig))
+        ));
+        assert_eq!(Balances::free_balance(bob()), 1_010);
+    });
+}
+
+#[test]
+fn ecdsa_signature_is_rejected_as_unsupported() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            bob(),
+            300,
+            60
+        ));
+        // a well formed ecdsa signature over the right voucher still does not pay
+        let voucher = Voucher {
+            sender: alice(),
+            receiver: bob(),
+            nonce: 0,
+            session_id: 1,
+            amount: 10,
+            valid_until: NO_EXPIRY,
+        };
+        let pair = sp_core::ecdsa::Pair::from_seed(&[1u8; 32]);
+        let sig = pair.sign(&Micropayment::voucher_hash(&voucher));
+        assert_noop!(
+            Micropayment::claim_payment(
+                Origin::signed(bob()),
+                alice(),
+                1,
+                10,
+                NO_EXPIRY,
+                TypedSignature::Ecdsa(sig)
+            ),
+            Error::<Test>::UnsupportedSignatureScheme
+        );
+    });
+}
+
+#[test]
+fn claim_best_settles_largest_valid_voucher() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            bob(),
+            300,
+            60
+        ));
+        let vouchers = vec![
+            (
+                40,
+                NO_EXPIRY,
+                sr(sign_voucher(&alice_pair(), &bob(), 0, 1, 40)),
+            ),
+            // signed by the wrong key
+            (
+                250,
+                NO_EXPIRY,
+                sr(sign_voucher(&charlie_pair(), &bob(), 0, 1, 250)),
+            ),
+            (
+                90,
+                NO_EXPIRY,
+                sr(sign_voucher(&alice_pair(), &bob(), 0, 1, 90)),
+            ),
+            // signature over a different amount
+            (
+                200,
+                NO_EXPIRY,
+                sr(sign_voucher(&alice_pair(), &bob(), 0, 1, 20)),
+            ),
+            (
+                60,
+                NO_EXPIRY,
+                sr(sign_voucher(&alice_pair(), &bob(), 0, 1, 60)),
+            ),
+        ];
+        assert_ok!(Micropayment::claim_best(
+            Origin::signed(bob()),
+            alice(),
+            1,
+            vouchers
+        ));
+        assert_eq!(Balances::free_balance(bob()), 1_090);
+        assert_eq!(Micropayment::get_channel((alice(), bob())).claimed, 90);
+        assert!(Micropayment::get_session_id((alice(), bob()), 1));
+        assert_eq!(
+            micropayment_events().last(),
+            Some(&RawEvent::BatchProcessed(bob(), 5, 3, 2))
+        );
+    });
+}
+
+#[test]
+fn claim_best_rejects_when_no_voucher_is_valid() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            bob(),
+            300,
+            60
+        ));
+        let vouchers = vec![
+            (
+                50,
+                NO_EXPIRY,
+                sr(sign_voucher(&charlie_pair(), &bob(), 0, 1, 50)),
+            ),
+            (70, NO_EXPIRY, sr(vec![0u8; 64])),
+        ];
+        assert_noop!(
+            Micropayment::claim_best(Origin::signed(bob()), alice(), 1, vouchers),
+            Error::<Test>::NoValidVoucher
+        );
+        assert_noop!(
+            Micropayment::claim_best(Origin::signed(bob()), alice(), 1, vec![]),
+            Error::<Test>::NoValidVoucher
+        );
+    });
+}
+
+#[test]
+fn open_channel_uses_configured_origin() {
+    new_test_ext().execute_with(|| {
+        // the mock resolves root to charlie, who funds the channel
+        assert_ok!(Micropayment::open_channel(Origin::root(), bob(), 200, 60));
+        let chan = Micropayment::get_channel((charlie(), bob()));
+        assert_eq!(chan.sender, charlie());
+        assert_eq!(Balances::reserved_balance(charlie()), 200);
+        assert_noop!(
+            Micropayment::open_channel(Origin::none(), bob(), 200, 60),
+            sp_runtime::DispatchError::BadOrigin
+        );
+    });
+}
+
+#[test]
+fn sender_cannot_close_channel() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            bob(),
+            300,
+            60
+        ));
+        assert_noop!(
+            Micropayment::close_channel(Origin::signed(alice()), alice(), false),
+            Error::<Test>::OnlyReceiverCanClose
+        );
+        assert_noop!(
+            Micropayment::close_channel(Origin::signed(alice()), bob(), false),
+            Error::<Test>::OnlyReceiverCanClose
+        );
+        assert_noop!(
+            Micropayment::close_channel(Origin::signed(charlie()), alice(), false),
+            "Channel not exists"
+        );
+    });
+}
+
+#[test]
+fn receiver_can_close_when_channels_go_both_ways() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            bob(),
+            300,
+            60
+        ));
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(bob()),
+            alice(),
+            300,
+            60
+        ));
+        assert_ok!(Micropayment::close_channel(
+            Origin::signed(bob()),
+            alice(),
+            false
+        ));
+        assert!(Channel::<Test>::contains_key((bob(), alice())));
+    });
+}
+
+#[test]
+fn total_locked_matches_reserved_deposits() {
+    new_test_ext().execute_with(|| {
+        let check_invariant = || {
+            let unclaimed: u64 = Channel::<Test>::iter()
+                .map(|(_, chan)| chan.deposit - chan.claimed)
+                .sum();
+            let reserved: u64 = [alice(), bob(), charlie()]
+                .iter()
+                .map(|who| Balances::reserved_balance(who))
+                .sum();
+            assert_eq!(Micropayment::total_locked(), unclaimed);
+            assert_eq!(Micropayment::total_locked(), reserved);
+        };
+
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            bob(),
+            300,
+            60
+        ));
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(bob()),
+            charlie(),
+            200,
+            60
+        ));
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            charlie(),
+            100,
+            60
+        ));
+        assert_eq!(Micropayment::total_locked(), 600);
+        check_invariant();
+
+        let sig = sign_voucher(&alice_pair(), &bob(), 0, 1, 120);
+        assert_ok!(Micropayment::claim_payment(
+            Origin::signed(bob()),
+            alice(),
+            1,
+            120,
+            NO_EXPIRY,
+            sr(sig)
+        ));
+        check_invariant();
+
+        let sig = sign_voucher(&bob_pair(), &charlie(), 0, 7, 200);
+        assert_ok!(Micropayment::claim_payment(
+            Origin::signed(charlie()),
+            bob(),
+            7,
+            200,
+            NO_EXPIRY,
+            sr(sig)
+        ));
+        check_invariant();
+
+        assert_ok!(Micropayment::close_channel(
+            Origin::signed(bob()),
+            alice(),
+            false
+        ));
+        check_invariant();
+        assert_ok!(Micropayment::close_channel(
+            Origin::signed(charlie()),
+            bob(),
+            false
+        ));
+        check_invariant();
+        assert_eq!(Micropayment::total_locked(), 100);
+    });
+}
+
+#[test]
+fn zero_claim_is_rejected() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            bob(),
+            300,
+            60
+        ));
+        let sig = sign_voucher(&alice_pair(), &bob(), 0, 1, 0);
+        assert_noop!(
+            Micropayment::claim_payment(Origin::signed(bob()), alice(), 1, 0, NO_EXPIRY, sr(sig)),
+            Error::<Test>::NothingToClaim
+        );
+        // nothing is claimable under a zero quota, which does not let a zero claim through
+        assert_ok!(Micropayment::set_receiver_quota(
+            Origin::signed(alice()),
+            bob(),
+            Some(0)
+        ));
+        let sig = sign_voucher(&alice_pair(), &bob(), 0, 1, 0);
+        assert_noop!(
+            Micropayment::claim_payment(Origin::signed(bob()), alice(), 1, 0, NO_EXPIRY, sr(sig)),
+            Error::<Test>::NothingToClaim
+        );
+        assert!(!Micropayment::get_session_id((alice(), bob()), 1));
+    });
+}
+
+#[test]
+fn claim_below_minimum_is_rejected() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            bob(),
+            300,
+            60
+        ));
+        let sig = sign_voucher(&alice_pair(), &bob(), 0, 1, 9);
+        assert_noop!(
+            Micropayment::claim_payment(Origin::signed(bob()), alice(), 1, 9, NO_EXPIRY, sr(sig)),
+            Error::<Test>::ClaimTooSmall
+        );
+        let sig = sign_voucher(&alice_pair(), &bob(), 0, 1, 10);
+        assert_ok!(Micropayment::claim_payment(
+            Origin::signed(bob()),
+            alice(),
+            1,
+            10,
+            NO_EXPIRY,
+            sr(sig)
+        ));
+    });
+}
+
+#[test]
+fn small_claim_exhausting_channel_is_allowed() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            bob(),
+            305,
+            60
+        ));
+        let sig = sign_voucher(&alice_pair(), &bob(), 0, 1, 300);
+        assert_ok!(Micropayment::claim_payment(
+            Origin::signed(bob()),
+            alice(),
+            1,
+            300,
+            NO_EXPIRY,
+            sr(sig)
+        ));
+        let sig = sign_voucher(&alice_pair(), &bob(), 0, 2, 5);
+        assert_ok!(Micropayment::claim_payment(
+            Origin::signed(bob()),
+            alice(),
+            2,
+            5,
+            NO_EXPIRY,
+            sr(sig)
+        ));
+        assert_eq!(Micropayment::get_channel((alice(), bob())).claimed, 305);
+    });
+}
+
+fn sign_reassign(
+    pair: &sr25519::Pair,
+    sender: &AccountId32,
+    new_receiver: &AccountId32,
+    nonce: u64,
+) -> Vec<u8> {
+    let msg = Micropayment::construct_reassign_hash(sender, new_receiver, nonce);
+    pair.sign(&msg).0.to_vec()
+}
+
+#[test]
+fn reassign_receiver_moves_channel() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            bob(),
+            300,
+            60
+        ));
+        let sig = sign_voucher(&alice_pair(), &bob(), 0, 1, 100);
+        assert_ok!(Micropayment::claim_payment(
+            Origin::signed(bob()),
+            alice(),
+            1,
+            100,
+            NO_EXPIRY,
+            sr(sig)
+        ));
+        let stale = sign_voucher(&alice_pair(), &bob(), 0, 2, 150);
+
+        let handoff = sign_reassign(&bob_pair(), &alice(), &charlie(), 0);
+        assert_ok!(Micropayment::reassign_receiver(
+            Origin::signed(charlie()),
+            alice(),
+            bob(),
+            charlie(),
+            sr(handoff)
+        ));
+        assert!(!Channel::<Test>::contains_key((alice(), bob())));
+        let chan = Micropayment::get_channel((alice(), charlie()));
+        assert_eq!(chan.receiver, charlie());
+        assert_eq!(chan.deposit, 300);
+        assert_eq!(chan.claimed, 100);
+        assert_eq!(chan.nonce, 0);
+        assert_eq!(Micropayment::get_nonce((alice(), charlie())), 1);
+
+        // vouchers signed for bob are bound to bob and do not carry over
+        assert_noop!(
+            Micropayment::claim_payment(
+                Origin::signed(charlie()),
+                alice(),
+                2,
+                150,
+                NO_EXPIRY,
+                sr(stale)
+            ),
+            "Fail to verify signature"
+        );
+        let sig = sign_voucher(&alice_pair(), &charlie(), 0, 2, 150);
+        assert_ok!(Micropayment::claim_payment(
+            Origin::signed(charlie()),
+            alice(),
+            2,
+            150,
+            NO_EXPIRY,
+            sr(sig)
+        ));
+        assert_eq!(Balances::free_balance(charlie()), 1_150);
+    });
+}
+
+#[test]
+fn reassign_receiver_requires_old_receiver_signature() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            bob(),
+            300,
+            60
+        ));
+        let forged = sign_reassign(&charlie_pair(), &alice(), &charlie(), 0);
+        assert_noop!(
+            Micropayment::reassign_receiver(
+                Origin::signed(charlie()),
+                alice(),
+                bob(),
+                charlie(),
+                sr(forged)
+            ),
+            "Fail to verify signature"
+        );
+    });
+}
+
+#[test]
+fn receiver_close_is_cooperative() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            bob(),
+            300,
+            60
+        ));
+        Timestamp::set_timestamp(10_000);
+        assert_ok!(Micropayment::close_channel(
+            Origin::signed(bob()),
+            alice(),
+            false
+        ));
+        assert!(micropayment_events().contains(&RawEvent::ChannelClosed(
+            alice(),
+            bob(),
+            10_000,
+            CloseKind::Cooperative
+        )));
+    });
+}
+
+#[test]
+fn force_close_is_forced() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            bob(),
+            300,
+            60
+        ));
+        assert_ok!(Micropayment::initiate_close(Origin::signed(alice()), bob()));
+        assert_noop!(
+            Micropayment::force_close_channel(Origin::signed(alice()), alice(), bob()),
+            DispatchError::BadOrigin
+        );
+        Timestamp::set_timestamp(10_000);
+        let free_before = Balances::free_balance(alice());
+        assert_ok!(Micropayment::force_close_channel(
+            Origin::root(),
+            alice(),
+            bob()
+        ));
+        assert_eq!(Balances::free_balance(alice()), free_before + 300);
+        assert!(!Channel::<Test>::contains_key((alice(), bob())));
+        assert_eq!(
+            micropayment_events().last(),
+            Some(&RawEvent::ChannelClosed(
+                alice(),
+                bob(),
+                10_000,
+                CloseKind::Forced
+            ))
+        );
+    });
+}
+
+#[test]
+fn claim_after_expiration_closes_as_expired() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            bob(),
+            300,
+            60
+        ));
+        Timestamp::set_timestamp(61_000);
+        let sig = sign_voucher(&alice_pair(), &bob(), 0, 1, 50);
+        assert_ok!(Micropayment::claim_payment(
+            Origin::signed(bob()),
+            alice(),
+            1,
+            50,
+            NO_EXPIRY,
+            sr(sig)
+        ));
+        assert!(micropayment_events().contains(&RawEvent::ChannelClosed(
+            alice(),
+            bob(),
+            61_000,
+            CloseKind::Expired
+        )));
+    });
+}
+
+#[test]
+fn claim_creates_fresh_receiver_account() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            dave(),
+            300,
+            60
+        ));
+        let sig = sign_voucher(&alice_pair(), &dave(), 0, 1, 50);
+        assert_ok!(Micropayment::claim_payment(
+            Origin::signed(dave()),
+            alice(),
+            1,
+            50,
+            NO_EXPIRY,
+            sr(sig)
+        ));
+        assert_eq!(Balances::free_balance(dave()), 50);
+        assert_eq!(Balances::free_balance(alice()), 700);
+        assert_eq!(Balances::reserved_balance(alice()), 250);
+    });
+}
+
+#[test]
+fn dust_claim_to_fresh_receiver_is_rejected() {
+    new_test_ext().execute_with(|| {
+        // a claim exhausting the channel may be below MinClaimAmount, but
+        // still can not create an account below the existential deposit
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            dave(),
+            3,
+            60
+        ));
+        let sig = sign_voucher(&alice_pair(), &dave(), 0, 1, 3);
+        assert_noop!(
+            Micropayment::claim_payment(Origin::signed(dave()), alice(), 1, 3, NO_EXPIRY, sr(sig)),
+            Error::<Test>::ReceiverBelowExistentialDeposit
+        );
+    });
+}
+
+#[test]
+fn consumed_session_ids_scans_range() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            bob(),
+            300,
+            60
+        ));
+        for id in &[1u32, 5, 9, MAX_SESSION_SCAN] {
+            let sig = sign_voucher(&alice_pair(), &bob(), 0, *id, 10);
+            assert_ok!(Micropayment::claim_payment(
+                Origin::signed(bob()),
+                alice(),
+                *id,
+                10,
+                NO_EXPIRY,
+                sr(sig)
+            ));
+        }
+        assert_eq!(
+            Micropayment::consumed_session_ids(&alice(), &bob(), 0, 10),
+            vec![1, 5, 9]
+        );
+        assert_eq!(
+            Micropayment::consumed_session_ids(&alice(), &bob(), 2, 5),
+            vec![5]
+        );
+        assert_eq!(
+            Micropayment::consumed_session_ids(&alice(), &bob(), 10, 20),
+            Vec::<u32>::new()
+        );
+        // the scan stops after MAX_SESSION_SCAN ids
+        assert_eq!(
+            Micropayment::consumed_session_ids(&alice(), &bob(), 0, u32::max_value()),
+            vec![1, 5, 9]
+        );
+        assert_eq!(
+            Micropayment::consumed_session_ids(&bob(), &alice(), 0, 10),
+            Vec::<u32>::new()
+        );
+    });
+}
+
+#[test]
+fn voucher_encoding_round_trips_and_verifies() {
+    new_test_ext().execute_with(|| {
+        let voucher = Voucher {
+            sender: alice(),
+            receiver: bob(),
+            nonce: 5u64,
+            session_id: 22u32,
+            amount: 100u64,
+            valid_until: 40u64,
+        };
+        let encoded = voucher.encode();
+        // fields are concatenated in declaration order, integers little endian
+        let mut expected = Vec::new();
+        expected.extend_from_slice(alice().as_ref());
+        expected.extend_from_slice(bob().as_ref());
+        expected.extend_from_slice(&5u64.to_le_bytes());
+        expected.extend_from_slice(&22u32.to_le_bytes());
+        expected.extend_from_slice(&100u64.to_le_bytes());
+        expected.extend_from_slice(&40u64.to_le_bytes());
+        assert_eq!(encoded, expected);
+
+        let decoded = Voucher::<AccountId32, u64, u64>::decode(&mut &encoded[..]).unwrap();
+        assert_eq!(decoded, voucher);
+
+        // the sender signs the domain tag followed by the encoding
+        let mut data = VOUCHER_DOMAIN.to_vec();
+        data.extend_from_slice(&encoded);
+        let msg = sp_io::hashing::blake2_256(&data);
+        assert_eq!(msg, Micropayment::voucher_hash(&decoded));
+        let sig = alice_pair().sign(&msg).0.to_vec();
+        assert_ok!(Micropayment::verify_signature(
+            &alice(),
+            &bob(),
+            5,
+            22,
+            100,
+            40,
+            &sig
+        ));
+        assert!(Micropayment::verify_signature(&alice(), &bob(), 5, 22, 101, 40, &sig).is_err());
+    });
+}
+
+#[test]
+fn channel_epoch_follows_reopen() {
+    new_test_ext().execute_with(|| {
+        assert_eq!(Micropayment::channel_epoch(&alice(), &bob()), None);
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            bob(),
+            300,
+            60
+        ));
+        assert_eq!(Micropayment::channel_epoch(&alice(), &bob()), Some(0));
+        assert_ok!(Micropayment::close_channel(
+            Origin::signed(bob()),
+            alice(),
+            false
+        ));
+        assert_eq!(Micropayment::channel_epoch(&alice(), &bob()), None);
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            bob(),
+            300,
+            60
+        ));
+        assert_eq!(Micropayment::channel_epoch(&alice(), &bob()), Some(1));
+        assert_eq!(Micropayment::channel_epoch(&bob(), &alice()), None);
+    });
+}
+
+#[test]
+fn quiet_claims_are_summed_up_at_block_end() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            bob(),
+            300,
+            60
+        ));
+        let events_before = micropayment_events().len();
+        for (session_id, amount) in &[(1, 10), (2, 20)] {
+            let sig = sign_voucher(&alice_pair(), &bob(), 0, *session_id, *amount);
+            assert_ok!(Micropayment::claim_payment_quiet(
+                Origin::signed(bob()),
+                alice(),
+                *session_id,
+                *amount,
+                NO_EXPIRY,
+                sr(sig)
+            ));
+        }
+        // funds moved and sessions are consumed, but nothing was announced yet
+        assert_eq!(Balances::free_balance(bob()), 1_030);
+        assert_eq!(Micropayment::get_channel((alice(), bob())).claimed, 30);
+        assert_eq!(Micropayment::total_locked(), 270);
+        assert_eq!(micropayment_events().len(), events_before);
+        let sig = sign_voucher(&alice_pair(), &bob(), 0, 1, 10);
+        assert_noop!(
+            Micropayment::claim_payment_quiet(
+                Origin::signed(bob()),
+                alice(),
+                1,
+                10,
+                NO_EXPIRY,
+                sr(sig)
+            ),
+            "SessionID already consumed"
+        );
+
+        Micropayment::on_finalize(1);
+        assert_eq!(
+            micropayment_events()[events_before..].to_vec(),
+            vec![
+                RawEvent::QuietClaimsSettled(2, 30),
+                RawEvent::TotalLockedChanged(270),
+            ]
+        );
+        assert_eq!(Micropayment::quiet_claims(), (0, 0));
+
+        // a block without quiet claims ends without a summary
+        Micropayment::on_finalize(2);
+        assert_eq!(micropayment_events().len(), events_before + 2);
+    });
+}
+
+#[test]
+fn remaining_claimable_follows_claims_and_quota() {
+    new_test_ext().execute_with(|| {
+        assert_eq!(Micropayment::remaining_claimable(&alice(), &bob()), 0);
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            bob(),
+            300,
+            60
+        ));
+        assert_eq!(Micropayment::remaining_claimable(&alice(), &bob()), 300);
+
+        let sig = sign_voucher(&alice_pair(), &bob(), 0, 1, 100);
+        assert_ok!(Micropayment::claim_payment(
+            Origin::signed(bob()),
+            alice(),
+            1,
+            100,
+            NO_EXPIRY,
+            sr(sig)
+        ));
+        assert_eq!(Micropayment::remaining_claimable(&alice(), &bob()), 200);
+
+        assert_ok!(Micropayment::set_receiver_quota(
+            Origin::signed(alice()),
+            bob(),
+            Some(150)
+        ));
+        assert_eq!(Micropayment::remaining_claimable(&alice(), &bob()), 50);
+
+        assert_ok!(Micropayment::set_receiver_quota(
+            Origin::signed(alice()),
+            bob(),
+            None
+        ));
+        let sig = sign_voucher(&alice_pair(), &bob(), 0, 2, 200);
+        assert_ok!(Micropayment::claim_payment(
+            Origin::signed(bob()),
+            alice(),
+            2,
+            200,
+            NO_EXPIRY,
+            sr(sig)
+        ));
+        assert_eq!(Micropayment::remaining_claimable(&alice(), &bob()), 0);
+    });
+}
+
+#[test]
+fn claim_payment_with_pubkey_checks_key_owner() {
+    new_test_ext().execute_with(|| {
+        Balances::make_free_balance_be(&eve(), 1_000);
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(eve()),
+            bob(),
+            300,
+            60
+        ));
+        let sig = sign_voucher_ecdsa(&eve_pair(), &bob(), 0, 1, 100);
+        let other = ecdsa::Pair::from_seed(&[6u8; 32]);
+        assert_noop!(
+            Micropayment::claim_payment_with_pubkey(
+                Origin::signed(bob()),
+                eve(),
+                ecdsa_pubkey(&other),
+                1,
+                100,
+                NO_EXPIRY,
+                sig.clone()
+            ),
+            Error::<Test>::PubkeyMismatch
+        );
+        assert_ok!(Micropayment::claim_payment_with_pubkey(
+            Origin::signed(bob()),
+            eve(),
+            ecdsa_pubkey(&eve_pair()),
+            1,
+            100,
+            NO_EXPIRY,
+            sig
+        ));
+        assert_eq!(Balances::free_balance(bob()), 1_100);
+    });
+}
+
+#[test]
+fn claim_payment_with_pubkey_verifies_signature() {
+    new_test_ext().execute_with(|| {
+        Balances::make_free_balance_be(&eve(), 1_000);
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(eve()),
+            bob(),
+            300,
+            60
+        ));
+        let other = ecdsa::Pair::from_seed(&[6u8; 32]);
+        let sig = sign_voucher_ecdsa(&other, &bob(), 0, 1, 100);
+        assert_noop!(
+            Micropayment::claim_payment_with_pubkey(
+                Origin::signed(bob()),
+                eve(),
+                ecdsa_pubkey(&eve_pair()),
+                1,
+                100,
+                NO_EXPIRY,
+                sig
+            ),
+            "Fail to verify signature"
+        );
+        // the key is a secp256k1 key, sr25519 signatures are refused outright
+        let sig = sign_voucher(&alice_pair(), &bob(), 0, 1, 100);
+        assert_noop!(
+            Micropayment::claim_payment_with_pubkey(
+                Origin::signed(bob()),
+                eve(),
+                ecdsa_pubkey(&eve_pair()),
+                1,
+                100,
+                NO_EXPIRY,
+                sr(sig)
+            ),
+            Error::<Test>::UnsupportedSignatureScheme
+        );
+    });
+}
+
+#[test]
+fn claim_best_refunds_weight_of_skipped_vouchers() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            bob(),
+            300,
+            60
+        ));
+        let vouchers = vec![
+            (
+                40,
+                NO_EXPIRY,
+                sr(sign_voucher(&alice_pair(), &bob(), 0, 1, 40)),
+            ),
+            // not an sr25519 signature, so never verified
+            (
+                250,
+                NO_EXPIRY,
+                TypedSignature::Ecdsa(ecdsa::Signature::from_raw([0u8; 65])),
+            ),
+            (
+                90,
+                NO_EXPIRY,
+                sr(sign_voucher(&alice_pair(), &bob(), 0, 1, 90)),
+            ),
+        ];
+        let info = Micropayment::claim_best(Origin::signed(bob()), alice(), 1, vouchers).unwrap();
+        assert_eq!(info.actual_weight, Some(2 * CLAIM_BEST_VOUCHER_WEIGHT));
+        assert_eq!(Micropayment::get_channel((alice(), bob())).claimed, 90);
+    });
+}
+
+#[test]
+fn claim_best_on_expired_channel_charges_one_voucher() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            bob(),
+            300,
+            60
+        ));
+        Timestamp::set_timestamp(61_000);
+        let vouchers = vec![
+            (
+                40,
+                NO_EXPIRY,
+                sr(sign_voucher(&alice_pair(), &bob(), 0, 1, 40)),
+            ),
+            (
+                90,
+                NO_EXPIRY,
+                sr(sign_voucher(&alice_pair(), &bob(), 0, 1, 90)),
+            ),
+            (
+                60,
+                NO_EXPIRY,
+                sr(sign_voucher(&alice_pair(), &bob(), 0, 1, 60)),
+            ),
+        ];
+        let info = Micropayment::claim_best(Origin::signed(bob()), alice(), 1, vouchers).unwrap();
+        assert_eq!(info.actual_weight, Some(CLAIM_BEST_VOUCHER_WEIGHT));
+        assert!(!Channel::<Test>::contains_key((alice(), bob())));
+    });
+}
+
+#[test]
+fn pair_summary_after_claims() {
+    new_test_ext().execute_with(|| {
+        assert_eq!(Micropayment::pair_summary(&alice(), &bob()), None);
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            bob(),
+            300,
+            60
+        ));
+        for (session_id, amount) in &[(3, 20), (7, 30), (5, 40)] {
+            let sig = sign_voucher(&alice_pair(), &bob(), 0, *session_id, *amount);
+            assert_ok!(Micropayment::claim_payment(
+                Origin::signed(bob()),
+                alice(),
+                *session_id,
+                *amount,
+                NO_EXPIRY,
+                sr(sig)
+            ));
+        }
+        let summary = Micropayment::pair_summary(&alice(), &bob()).unwrap();
+        assert_eq!(
+            summary.channel,
+            Some(Micropayment::get_channel((alice(), bob())))
+        );
+        assert_eq!(summary.epoch, 1);
+        assert_eq!(summary.claimed, 90);
+        assert_eq!(summary.highest_session_id, Some(7));
+        // the reverse pair never had a channel
+        assert_eq!(Micropayment::pair_summary(&bob(), &alice()), None);
+
+        assert_ok!(Micropayment::close_channel(
+            Origin::signed(bob()),
+            alice(),
+            false
+        ));
+        let summary = Micropayment::pair_summary(&alice(), &bob()).unwrap();
+        assert_eq!(summary.channel, None);
+        assert_eq!(summary.epoch, 1);
+        assert_eq!(summary.claimed, 0);
+        assert_eq!(summary.highest_session_id, None);
+    });
+}
+
+#[test]
+fn extend_channel_pushes_expiration() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            bob(),
+            300,
+            60
+        ));
+        assert_noop!(
+            Micropayment::extend_channel(Origin::signed(bob()), alice(), 30_000),
+            "Channel not exists"
+        );
+        assert_ok!(Micropayment::extend_channel(
+            Origin::signed(alice()),
+            bob(),
+            30_000
+        ));
+        assert_eq!(
+            Micropayment::get_channel((alice(), bob())).expiration,
+            90_000
+        );
+        assert_eq!(
+            micropayment_events().last(),
+            Some(&RawEvent::ChannelExtended(alice(), bob(), 90_000))
+        );
+
+        // still open past the original expiration
+        Timestamp::set_timestamp(61_000);
+        let sig = sign_voucher(&alice_pair(), &bob(), 0, 1, 100);
+        assert_ok!(Micropayment::claim_payment(
+            Origin::signed(bob()),
+            alice(),
+            1,
+            100,
+            NO_EXPIRY,
+            sr(sig)
+        ));
+        assert_eq!(Micropayment::get_channel((alice(), bob())).claimed, 100);
+    });
+}
+
+#[test]
+fn expired_channel_cannot_be_extended() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            bob(),
+            300,
+            60
+        ));
+        Timestamp::set_timestamp(61_000);
+        assert_noop!(
+            Micropayment::extend_channel(Origin::signed(alice()), bob(), 30_000),
+            Error::<Test>::ChannelExpired
+        );
+    });
+}
+
+#[test]
+fn settlement_seq_increases_across_channels() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            bob(),
+            300,
+            60
+        ));
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(charlie()),
+            bob(),
+            300,
+            60
+        ));
+        let claims = [
+            (alice_pair(), 1, 20),
+            (charlie_pair(), 1, 30),
+            (alice_pair(), 2, 40),
+            (charlie_pair(), 2, 50),
+        ];
+        for (pair, session_id, amount) in claims.iter() {
+            let sig = sign_voucher(pair, &bob(), 0, *session_id, *amount);
+            assert_ok!(Micropayment::claim_payment(
+                Origin::signed(bob()),
+                account_of(pair),
+                *session_id,
+                *amount,
+                NO_EXPIRY,
+                sr(sig)
+            ));
+        }
+        let seqs: Vec<u64> = micropayment_events()
+            .into_iter()
+            .filter_map(|e| match e {
+                RawEvent::ClaimPayment(_, _, _, seq) => Some(seq),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(seqs, vec![1, 2, 3, 4]);
+        assert_eq!(Micropayment::settlement_seq(), 4);
+    });
+}
+
+#[test]
+fn channels_of_tracks_open_channels() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            bob(),
+            300,
+            60
+        ));
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            charlie(),
+            300,
+            60
+        ));
+        assert_eq!(Micropayment::channels_of(alice()), vec![bob(), charlie()]);
+        assert_ok!(Micropayment::close_channel(
+            Origin::signed(bob()),
+            alice(),
+            false
+        ));
+        assert_eq!(Micropayment::channels_of(alice()), vec![charlie()]);
+        assert_ok!(Micropayment::close_channel(
+            Origin::signed(charlie()),
+            alice(),
+            false
+        ));
+        assert!(!ChannelsOf::<Test>::contains_key(alice()));
+    });
+}
+
+#[test]
+fn close_all_for_sender_winds_down_in_batches() {
+    new_test_ext().execute_with(|| {
+        for receiver in &[bob(), charlie(), dave()] {
+            assert_ok!(Micropayment::open_channel(
+                Origin::signed(alice()),
+                receiver.clone(),
+                100,
+                60
+            ));
+        }
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            AccountId32::from([9u8; 32]),
+            100,
+            600
+        ));
+        assert_eq!(Balances::reserved_balance(alice()), 400);
+        Timestamp::set_timestamp(61_000);
+
+        // MaxCloseBatch is 2 in the mock
+        assert_ok!(Micropayment::close_all_for_sender(Origin::signed(alice())));
+        assert_eq!(
+            micropayment_events().last(),
+            Some(&RawEvent::CloseBatchProcessed(alice(), 2, 2))
+        );
+        assert_ok!(Micropayment::close_all_for_sender(Origin::signed(alice())));
+        assert_eq!(
+            micropayment_events().last(),
+            Some(&RawEvent::CloseBatchProcessed(alice(), 1, 1))
+        );
+        // the channel that has not expired yet starts closing
+        let nine = AccountId32::from([9u8; 32]);
+        assert_eq!(Micropayment::channels_of(alice()), vec![nine.clone()]);
+        assert_eq!(
+            Micropayment::get_channel((alice(), nine.clone())).state,
+            ChannelState::Closing { at: 71_000 }
+        );
+        assert_eq!(Balances::reserved_balance(alice()), 100);
+
+        // and is refunded once its receiver had ChallengePeriod to claim
+        assert_ok!(Micropayment::close_all_for_sender(Origin::signed(alice())));
+        assert_eq!(
+            micropayment_events().last(),
+            Some(&RawEvent::CloseBatchProcessed(alice(), 0, 1))
+        );
+        Timestamp::set_timestamp(71_000);
+        assert_ok!(Micropayment::close_all_for_sender(Origin::signed(alice())));
+        assert!(micropayment_events().contains(&RawEvent::ChannelClosed(
+            alice(),
+            nine,
+            71_000,
+            CloseKind::Unilateral
+        )));
+        assert_eq!(
+            micropayment_events().last(),
+            Some(&RawEvent::CloseBatchProcessed(alice(), 1, 0))
+        );
+        assert!(Micropayment::channels_of(alice()).is_empty());
+        assert_eq!(Balances::reserved_balance(alice()), 0);
+        assert_eq!(Micropayment::total_locked(), 0);
+    });
+}
+
+#[test]
+fn close_all_for_sender_charges_channels_looked_at() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            bob(),
+            100,
+            60
+        ));
+        let info = Micropayment::close_all_for_sender(Origin::signed(alice())).unwrap();
+        assert_eq!(info.actual_weight, Some(CLOSE_ALL_CHANNEL_WEIGHT));
+    });
+}
+
+#[test]
+fn open_channel_calls_hook() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            bob(),
+            300,
+            60
+        ));
+        // a failed open does not reach the hook
+        assert_noop!(
+            Micropayment::open_channel(Origin::signed(alice()), bob(), 300, 60),
+            "Channel already opened"
+        );
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(charlie()),
+            bob(),
+            100,
+            60
+        ));
+        assert_eq!(
+            opened_channels(),
+            vec![(alice(), bob(), 300), (charlie(), bob(), 100)]
+        );
+    });
+}
+
+#[test]
+fn invalidated_session_id_cannot_be_claimed() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            bob(),
+            300,
+            60
+        ));
+        assert_noop!(
+            Micropayment::invalidate_session_id(Origin::signed(bob()), alice(), 1),
+            "Channel not exists"
+        );
+        assert_ok!(Micropayment::invalidate_session_id(
+            Origin::signed(alice()),
+            bob(),
+            1
+        ));
+        assert_eq!(
+            micropayment_events().last(),
+            Some(&RawEvent::SessionIdInvalidated(alice(), bob(), 1))
+        );
+        let sig = sign_voucher(&alice_pair(), &bob(), 0, 1, 100);
+        assert_noop!(
+            Micropayment::claim_payment(Origin::signed(bob()), alice(), 1, 100, NO_EXPIRY, sr(sig)),
+            "SessionID already consumed"
+        );
+        assert_noop!(
+            Micropayment::invalidate_session_id(Origin::signed(alice()), bob(), 1),
+            "SessionID already consumed"
+        );
+        // other sessions are unaffected
+        let sig = sign_voucher(&alice_pair(), &bob(), 0, 2, 100);
+        assert_ok!(Micropayment::claim_payment(
+            Origin::signed(bob()),
+            alice(),
+            2,
+            100,
+            NO_EXPIRY,
+            sr(sig)
+        ));
+    });
+}
+
+#[test]
+fn session_id_cannot_be_invalidated_while_closing() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            bob(),
+            300,
+            60
+        ));
+        assert_ok!(Micropayment::initiate_close(Origin::signed(alice()), bob()));
+        assert_noop!(
+            Micropayment::invalidate_session_id(Origin::signed(alice()), bob(), 1),
+            Error::<Test>::ChannelNotOpen
+        );
+        // bob's voucher for session 1 stays claimable in the challenge period
+        let sig = sign_voucher(&alice_pair(), &bob(), 0, 1, 100);
+        assert_ok!(Micropayment::claim_payment(
+            Origin::signed(bob()),
+            alice(),
+            1,
+            100,
+            NO_EXPIRY,
+            sr(sig)
+        ));
+        assert_eq!(Balances::free_balance(bob()), 1_100);
+    });
+}
+
+#[test]
+fn claim_aggregate_consumes_lower_sessions() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            bob(),
+            300,
+            60
+        ));
+        let sig = sign_voucher(&alice_pair(), &bob(), 0, 2, 20);
+        assert_ok!(Micropayment::claim_payment(
+            Origin::signed(bob()),
+            alice(),
+            2,
+            20,
+            NO_EXPIRY,
+            sr(sig)
+        ));
+
+        // one voucher for session 5 covers sessions 0 to 4 as well
+        let sig = sign_voucher(&alice_pair(), &bob(), 0, 5, 100);
+        assert_ok!(Micropayment::claim_aggregate(
+            Origin::signed(bob()),
+            alice(),
+            5,
+            100,
+            NO_EXPIRY,
+            sr(sig)
+        ));
+        assert_eq!(Balances::free_balance(bob()), 1_120);
+        assert_eq!(
+            micropayment_events().last(),
+            Some(&RawEvent::SessionsSuperseded(alice(), bob(), 5))
+        );
+        assert_eq!(
+            Micropayment::consumed_session_ids(&alice(), &bob(), 0, 8),
+            vec![0, 1, 2, 3, 4, 5]
+        );
+        assert_eq!(
+            Micropayment::pair_summary(&alice(), &bob())
+                .unwrap()
+                .highest_session_id,
+            Some(5)
+        );
+
+        // intermediate sessions can not be claimed on their own any more
+        let sig = sign_voucher(&alice_pair(), &bob(), 0, 3, 30);
+        assert_noop!(
+            Micropayment::claim_payment(Origin::signed(bob()), alice(), 3, 30, NO_EXPIRY, sr(sig)),
+            "SessionID already consumed"
+        );
+        let sig = sign_voucher(&alice_pair(), &bob(), 0, 4, 30);
+        assert_noop!(
+            Micropayment::claim_aggregate(
+                Origin::signed(bob()),
+                alice(),
+                4,
+                30,
+                NO_EXPIRY,
+                sr(sig)
+            ),
+            "SessionID already consumed"
+        );
+        // later sessions still can
+        let sig = sign_voucher(&alice_pair(), &bob(), 0, 6, 30);
+        assert_ok!(Micropayment::claim_payment(
+            Origin::signed(bob()),
+            alice(),
+            6,
+            30,
+            NO_EXPIRY,
+            sr(sig)
+        ));
+
+        // a new channel starts from session 0 again
+        assert_ok!(Micropayment::close_channel(
+            Origin::signed(bob()),
+            alice(),
+            false
+        ));
+        assert_eq!(Micropayment::session_floor((alice(), bob())), 0);
+    });
+}
+
+#[test]
+fn claim_delegate_claims_for_receiver() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            bob(),
+            300,
+            60
+        ));
+        let sig = sign_voucher(&alice_pair(), &bob(), 0, 1, 50);
+        assert_noop!(
+            Micropayment::delegate_claim(
+                Origin::signed(charlie()),
+                bob(),
+                alice(),
+                1,
+                50,
+                NO_EXPIRY,
+                sr(sig.clone())
+            ),
+            Error::<Test>::NotClaimDelegate
+        );
+
+        assert_ok!(Micropayment::add_claim_delegate(
+            Origin::signed(bob()),
+            charlie()
+        ));
+        assert_eq!(
+            micropayment_events().last(),
+            Some(&RawEvent::ClaimDelegateAdded(bob(), charlie()))
+        );
+        assert_ok!(Micropayment::delegate_claim(
+            Origin::signed(charlie()),
+            bob(),
+            alice(),
+            1,
+            50,
+            NO_EXPIRY,
+            sr(sig)
+        ));
+        // the receiver is paid, not the delegate
+        assert_eq!(Balances::free_balance(bob()), 1_050);
+        assert_eq!(Balances::free_balance(charlie()), 1_000);
+
+        assert_ok!(Micropayment::remove_claim_delegate(
+            Origin::signed(bob()),
+            charlie()
+        ));
+        assert!(!Micropayment::is_claim_delegate(bob(), charlie()));
+        let sig = sign_voucher(&alice_pair(), &bob(), 0, 2, 50);
+        assert_noop!(
+            Micropayment::delegate_claim(
+                Origin::signed(charlie()),
+                bob(),
+                alice(),
+                2,
+                50,
+                NO_EXPIRY,
+                sr(sig)
+            ),
+            Error::<Test>::NotClaimDelegate
+        );
+        assert_noop!(
+            Micropayment::remove_claim_delegate(Origin::signed(bob()), charlie()),
+            Error::<Test>::NotClaimDelegate
+        );
+    });
+}
+
+#[test]
+fn receiver_count_tracks_opens_and_closes() {
+    new_test_ext().execute_with(|| {
+        assert_eq!(Micropayment::receiver_count(alice()), 0);
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            bob(),
+            100,
+            60
+        ));
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            charlie(),
+            100,
+            60
+        ));
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            dave(),
+            100,
+            600
+        ));
+        assert_eq!(Micropayment::receiver_count(alice()), 3);
+
+        assert_ok!(Micropayment::close_channel(
+            Origin::signed(bob()),
+            alice(),
+            false
+        ));
+        assert_eq!(Micropayment::receiver_count(alice()), 2);
+
+        // the expired channel to charlie is closed, the one to dave is kept
+        Timestamp::set_timestamp(61_000);
+        assert_ok!(Micropayment::close_all_for_sender(Origin::signed(alice())));
+        assert_eq!(Micropayment::receiver_count(alice()), 1);
+        assert_eq!(
+            Micropayment::receiver_count(alice()) as usize,
+            Micropayment::channels_of(alice()).len()
+        );
+
+        assert_ok!(Micropayment::close_channel(
+            Origin::signed(dave()),
+            alice(),
+            false
+        ));
+        assert!(!ReceiverCount::<Test>::contains_key(alice()));
+    });
+}
+
+#[test]
+fn error_codes_are_stable() {
+    let code = |e: Error<Test>| match DispatchError::from(e) {
+        DispatchError::Module { error, .. } => error,
+        _ => unreachable!(),
+    };
+    assert_eq!(code(Error::<Test>::SignatureTooLong), 0);
+    assert_eq!(code(Error::<Test>::InvalidSignature), 1);
+    assert_eq!(code(Error::<Test>::NoValidVoucher), 2);
+    assert_eq!(code(Error::<Test>::OnlyReceiverCanClose), 3);
+    assert_eq!(code(Error::<Test>::ClaimTooSmall), 4);
+    assert_eq!(code(Error::<Test>::ReceiverBelowExistentialDeposit), 5);
+    assert_eq!(code(Error::<Test>::PubkeyMismatch), 6);
+    assert_eq!(code(Error::<Test>::ChannelExpired), 7);
+    assert_eq!(code(Error::<Test>::NotStreamChannel), 8);
+    assert_eq!(code(Error::<Test>::NothingToClaim), 9);
+    assert_eq!(code(Error::<Test>::SelfPayment), 10);
+    assert_eq!(code(Error::<Test>::VoucherExpired), 11);
+    assert_eq!(code(Error::<Test>::ChannelNotOpen), 12);
+    assert_eq!(code(Error::<Test>::ChannelNotClosing), 13);
+    assert_eq!(code(Error::<Test>::ChallengePeriodNotOver), 14);
+    assert_eq!(code(Error::<Test>::ChannelSettled), 15);
+    assert_eq!(code(Error::<Test>::QuotaExceeded), 16);
+    assert_eq!(code(Error::<Test>::QuotaBelowClaimed), 17);
+    assert_eq!(code(Error::<Test>::ClaimAboveMax), 18);
+    assert_eq!(code(Error::<Test>::InsufficientBalance), 19);
+    assert_eq!(code(Error::<Test>::UnsupportedSignatureScheme), 20);
+    assert_eq!(code(Error::<Test>::SettlementPending), 21);
+    assert_eq!(code(Error::<Test>::NoPendingSettlement), 22);
+    assert_eq!(code(Error::<Test>::ClaimGracePeriodNotOver), 23);
+    assert_eq!(code(Error::<Test>::NotClaimDelegate), 24);
+    assert_eq!(code(Error::<Test>::RateLimited), 25);
+    assert_eq!(code(Error::<Test>::SettlementWindowNotOver), 26);
+    assert_eq!(code(Error::<Test>::InsufficientReserve), 27);
+}
+
+#[test]
+fn claim_stream_pays_rate_per_block() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(Micropayment::open_stream_channel(
+            Origin::signed(alice()),
+            bob(),
+            300,
+            60,
+            10
+        ));
+        assert_noop!(
+            Micropayment::claim_stream(Origin::signed(bob()), alice()),
+            Error::<Test>::NothingToClaim
+        );
+
+        System::set_block_number(6);
+        assert_ok!(Micropayment::claim_stream(Origin::signed(bob()), alice()));
+        assert_eq!(Balances::free_balance(bob()), 1_050);
+        assert_eq!(Micropayment::get_channel((alice(), bob())).claimed, 50);
+
+        System::set_block_number(9);
+        assert_ok!(Micropayment::claim_stream(Origin::signed(bob()), alice()));
+        assert_eq!(Micropayment::get_channel((alice(), bob())).claimed, 80);
+        assert_eq!(
+            Micropayment::get_stream((alice(), bob()))
+                .unwrap()
+                .last_claim,
+            9
+        );
+
+        // accrual is capped at what is left of the deposit
+        System::set_block_number(100);
+        assert_ok!(Micropayment::claim_stream(Origin::signed(bob()), alice()));
+        assert_eq!(Micropayment::get_channel((alice(), bob())).claimed, 300);
+        assert_eq!(Balances::reserved_balance(alice()), 0);
+        System::set_block_number(101);
+        assert_noop!(
+            Micropayment::claim_stream(Origin::signed(bob()), alice()),
+            Error::<Test>::NothingToClaim
+        );
+    });
+}
+
+#[test]
+fn stream_withdrawal_below_minimum_waits() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(Micropayment::open_stream_channel(
+            Origin::signed(alice()),
+            bob(),
+            300,
+            60,
+            3
+        ));
+        // MinClaimAmount is 10 in the mock
+        System::set_block_number(3);
+        assert_noop!(
+            Micropayment::claim_stream(Origin::signed(bob()), alice()),
+            Error::<Test>::ClaimTooSmall
+        );
+        System::set_block_number(5);
+        assert_ok!(Micropayment::claim_stream(Origin::signed(bob()), alice()));
+        assert_eq!(Micropayment::get_channel((alice(), bob())).claimed, 12);
+    });
+}
+
+#[test]
+fn claim_stream_requires_stream_channel() {
+    new_test_ext().execute_with(|| {
+        assert_noop!(
+            Micropayment::open_stream_channel(Origin::signed(alice()), bob(), 300, 60, 0),
+            Error::<Test>::NotStreamChannel
+        );
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            bob(),
+            300,
+            60
+        ));
+        System::set_block_number(6);
+        assert_noop!(
+            Micropayment::claim_stream(Origin::signed(bob()), alice()),
+            Error::<Test>::NotStreamChannel
+        );
+    });
+}
+
+#[test]
+fn closing_stream_channel_clears_stream() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(Micropayment::open_stream_channel(
+            Origin::signed(alice()),
+            bob(),
+            300,
+            60,
+            10
+        ));
+        System::set_block_number(4);
+        assert_ok!(Micropayment::claim_stream(Origin::signed(bob()), alice()));
+        assert_ok!(Micropayment::close_channel(
+            Origin::signed(bob()),
+            alice(),
+            false
+        ));
+        assert_eq!(Micropayment::get_stream((alice(), bob())), None);
+        assert_eq!(Balances::free_balance(alice()), 970);
+    });
+}
+
+#[test]
+fn settle_voucher_can_be_called_directly() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            bob(),
+            300,
+            60
+        ));
+        let sig = sign_voucher(&alice_pair(), &bob(), 0, 1, 100);
+        assert_ok!(Micropayment::settle_voucher(
+            &alice(),
+            &bob(),
+            1,
+            100,
+            NO_EXPIRY,
+            &sig
+        ));
+        assert_eq!(Balances::free_balance(bob()), 1_100);
+        assert_noop!(
+            Micropayment::settle_voucher(&alice(), &bob(), 1, 100, NO_EXPIRY, &sig),
+            "SessionID already consumed"
+        );
+        let sig = sign_voucher(&charlie_pair(), &bob(), 0, 2, 100);
+        assert_noop!(
+            Micropayment::settle_voucher(&alice(), &bob(), 2, 100, NO_EXPIRY, &sig),
+            "Fail to verify signature"
+        );
+
+        // expired channels are not closed behind the caller's back
+        Timestamp::set_timestamp(61_000);
+        let sig = sign_voucher(&alice_pair(), &bob(), 0, 2, 100);
+        assert_noop!(
+            Micropayment::settle_voucher(&alice(), &bob(), 2, 100, NO_EXPIRY, &sig),
+            Error::<Test>::ChannelExpired
+        );
+        assert!(Channel::<Test>::contains_key((alice(), bob())));
+    });
+}
+
+#[test]
+fn open_channel_to_own_proxy_is_rejected() {
+    new_test_ext().execute_with(|| {
+        // root opens on behalf of charlie, dave is charlie's proxy in the mock
+        assert_noop!(
+            Micropayment::open_channel(Origin::root(), dave(), 100, 60),
+            Error::<Test>::SelfPayment
+        );
+        assert_noop!(
+            Micropayment::open_stream_channel(Origin::signed(charlie()), dave(), 100, 60, 1),
+            Error::<Test>::SelfPayment
+        );
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            dave(),
+            100,
+            60
+        ));
+    });
+}
+
+#[test]
+fn reassign_to_own_proxy_is_rejected() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(charlie()),
+            bob(),
+            100,
+            60
+        ));
+        // bob cooperates in handing charlie's channel over to charlie's proxy dave
+        let handoff = sign_reassign(&bob_pair(), &charlie(), &dave(), 0);
+        assert_noop!(
+            Micropayment::reassign_receiver(
+                Origin::signed(charlie()),
+                charlie(),
+                bob(),
+                dave(),
+                sr(handoff)
+            ),
+            Error::<Test>::SelfPayment
+        );
+    });
+}
+
+#[test]
+fn voucher_expires_after_valid_until() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            bob(),
+            300,
+            60
+        ));
+        let sig = sign_voucher_until(&alice_pair(), &bob(), 0, 1, 100, 5);
+        System::set_block_number(5);
+        assert_ok!(Micropayment::claim_payment(
+            Origin::signed(bob()),
+            alice(),
+            1,
+            100,
+            5,
+            sr(sig)
+        ));
+
+        let sig = sign_voucher_until(&alice_pair(), &bob(), 0, 2, 100, 5);
+        System::set_block_number(6);
+        assert_noop!(
+            Micropayment::claim_payment(Origin::signed(bob()), alice(), 2, 100, 5, sr(sig.clone())),
+            Error::<Test>::VoucherExpired
+        );
+        // valid_until is signed, pushing it back breaks the signature
+        assert_noop!(
+            Micropayment::claim_payment(Origin::signed(bob()), alice(), 2, 100, 10, sr(sig)),
+            "Fail to verify signature"
+        );
+    });
+}
+
+#[test]
+fn claim_best_skips_expired_vouchers() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            bob(),
+            300,
+            60
+        ));
+        System::set_block_number(10);
+        let vouchers = vec![
+            (
+                90,
+                9,
+                sr(sign_voucher_until(&alice_pair(), &bob(), 0, 1, 90, 9)),
+            ),
+            (
+                60,
+                10,
+                sr(sign_voucher_until(&alice_pair(), &bob(), 0, 1, 60, 10)),
+            ),
+        ];
+        assert_ok!(Micropayment::claim_best(
+            Origin::signed(bob()),
+            alice(),
+            1,
+            vouchers
+        ));
+        assert_eq!(Micropayment::get_channel((alice(), bob())).claimed, 60);
+    });
+}
+
+#[test]
+fn channel_lifecycle_event_order() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            bob(),
+            300,
+            60
+        ));
+        let sig = sign_voucher(&alice_pair(), &bob(), 0, 1, 100);
+        assert_ok!(Micropayment::claim_payment(
+            Origin::signed(bob()),
+            alice(),
+            1,
+            100,
+            NO_EXPIRY,
+            sr(sig)
+        ));
+        assert_ok!(Micropayment::close_channel(
+            Origin::signed(bob()),
+            alice(),
+            false
+        ));
+        assert_eq!(
+            micropayment_events(),
+            vec![
+                // open_channel
+                RawEvent::ChannelOpened(alice(), bob(), 300, 0, 0, 60_000),
+                RawEvent::TotalLockedChanged(300),
+                // claim_payment
+                RawEvent::TotalLockedChanged(200),
+                RawEvent::ClaimPayment(alice(), bob(), 100, 1),
+                // close_channel
+                RawEvent::TotalLockedChanged(0),
+                RawEvent::ChannelClosed(alice(), bob(), 0, CloseKind::Cooperative),
+            ]
+        );
+    });
+}
+
+#[test]
+fn expired_claim_event_order() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            bob(),
+            300,
+            60
+        ));
+        System::reset_events();
+        Timestamp::set_timestamp(61_000);
+        let sig = sign_voucher(&alice_pair(), &bob(), 0, 1, 100);
+        assert_ok!(Micropayment::claim_payment(
+            Origin::signed(bob()),
+            alice(),
+            1,
+            100,
+            NO_EXPIRY,
+            sr(sig)
+        ));
+        // the claim is not paid, the channel is refunded and closed
+        assert_eq!(
+            micropayment_events(),
+            vec![
+                RawEvent::TotalLockedChanged(0),
+                RawEvent::ChannelClosed(alice(), bob(), 61_000, CloseKind::Expired),
+            ]
+        );
+    });
+}
+
+#[test]
+fn sender_close_goes_through_challenge_period() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            bob(),
+            300,
+            60
+        ));
+        assert_noop!(
+            Micropayment::finalize_close(Origin::signed(alice()), bob()),
+            Error::<Test>::ChannelNotClosing
+        );
+        Timestamp::set_timestamp(1_000);
+        assert_ok!(Micropayment::initiate_close(Origin::signed(alice()), bob()));
+        assert_eq!(
+            Micropayment::get_channel((alice(), bob())).state,
+            ChannelState::Closing { at: 11_000 }
+        );
+        assert_eq!(
+            micropayment_events().last(),
+            Some(&RawEvent::ChannelClosing(alice(), bob(), 11_000))
+        );
+        assert_noop!(
+            Micropayment::initiate_close(Origin::signed(alice()), bob()),
+            Error::<Test>::ChannelNotOpen
+        );
+        assert_noop!(
+            Micropayment::extend_channel(Origin::signed(alice()), bob(), 30_000),
+            Error::<Test>::ChannelNotOpen
+        );
+
+        // the receiver can still claim while the channel is closing
+        let sig = sign_voucher(&alice_pair(), &bob(), 0, 1, 100);
+        assert_ok!(Micropayment::claim_payment(
+            Origin::signed(bob()),
+            alice(),
+            1,
+            100,
+            NO_EXPIRY,
+            sr(sig)
+        ));
+
+        Timestamp::set_timestamp(10_999);
+        assert_noop!(
+            Micropayment::finalize_close(Origin::signed(alice()), bob()),
+            Error::<Test>::ChallengePeriodNotOver
+        );
+        Timestamp::set_timestamp(11_000);
+        assert_ok!(Micropayment::finalize_close(Origin::signed(alice()), bob()));
+        assert!(!Channel::<Test>::contains_key((alice(), bob())));
+        assert_eq!(Balances::free_balance(alice()), 900);
+        assert_eq!(
+            micropayment_events().last(),
+            Some(&RawEvent::ChannelClosed(
+                alice(),
+                bob(),
+                11_000,
+                CloseKind::Unilateral
+            ))
+        );
+    });
+}
+
+#[test]
+fn claims_wait_for_grace_period() {
+    new_test_ext().execute_with(|| {
+        set_grace_period(2);
+        assert_ok!(Micropayment::open_stream_channel(
+            Origin::signed(alice()),
+            bob(),
+            300,
+            60,
+            10
+        ));
+        let sig = sign_voucher(&alice_pair(), &bob(), 0, 1, 100);
+        System::set_block_number(2);
+        assert_noop!(
+            Micropayment::claim_payment(
+                Origin::signed(bob()),
+                alice(),
+                1,
+                100,
+                NO_EXPIRY,
+                sr(sig.clone())
+            ),
+            Error::<Test>::ClaimGracePeriodNotOver
+        );
+        assert_noop!(
+            Micropayment::claim_stream(Origin::signed(bob()), alice()),
+            Error::<Test>::ClaimGracePeriodNotOver
+        );
+
+        System::set_block_number(3);
+        assert_ok!(Micropayment::claim_payment(
+            Origin::signed(bob()),
+            alice(),
+            1,
+            100,
+            NO_EXPIRY,
+            sr(sig)
+        ));
+        assert_ok!(Micropayment::claim_stream(Origin::signed(bob()), alice()));
+        assert_eq!(Balances::free_balance(bob()), 1_120);
+        // past the grace period the sender needs the challenge period again
+        assert_noop!(
+            Micropayment::finalize_close(Origin::signed(alice()), bob()),
+            Error::<Test>::ChannelNotClosing
+        );
+    });
+}
+
+#[test]
+fn sender_cancels_channel_during_grace_period() {
+    new_test_ext().execute_with(|| {
+        set_grace_period(2);
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            bob(),
+            300,
+            60
+        ));
+        System::set_block_number(2);
+        assert_ok!(Micropayment::finalize_close(Origin::signed(alice()), bob()));
+        assert!(!Channel::<Test>::contains_key((alice(), bob())));
+        assert_eq!(Balances::free_balance(alice()), 1_000);
+        assert_eq!(Balances::reserved_balance(alice()), 0);
+    });
+}
+
+#[test]
+fn challenge_window_follows_channel_state() {
+    new_test_ext().execute_with(|| {
+        let in_window = || Micropayment::is_in_challenge_window(&alice(), &bob());
+        assert!(!in_window());
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            bob(),
+            300,
+            60
+        ));
+        assert!(!in_window());
+
+        Timestamp::set_timestamp(1_000);
+        assert_ok!(Micropayment::initiate_close(Origin::signed(alice()), bob()));
+        assert!(in_window());
+        Timestamp::set_timestamp(10_999);
+        assert!(in_window());
+        Timestamp::set_timestamp(11_000);
+        assert!(!in_window());
+        assert_ok!(Micropayment::finalize_close(Origin::signed(alice()), bob()));
+        assert!(!in_window());
+
+        // a settled channel has nothing left to challenge for
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            bob(),
+            100,
+            60
+        ));
+        assert_ok!(Micropayment::initiate_close(Origin::signed(alice()), bob()));
+        assert!(in_window());
+        let sig = sign_voucher(&alice_pair(), &bob(), 1, 1, 100);
+        assert_ok!(Micropayment::claim_payment(
+            Origin::signed(bob()),
+            alice(),
+            1,
+            100,
+            NO_EXPIRY,
+            sr(sig)
+        ));
+        assert_eq!(
+            Micropayment::get_channel((alice(), bob())).state,
+            ChannelState::Settled
+        );
+        assert!(!in_window());
+    });
+}
+
+#[test]
+fn exhausted_channel_is_settled() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            bob(),
+            100,
+            60
+        ));
+        let sig = sign_voucher(&alice_pair(), &bob(), 0, 1, 100);
+        assert_ok!(Micropayment::claim_payment(
+            Origin::signed(bob()),
+            alice(),
+            1,
+            100,
+            NO_EXPIRY,
+            sr(sig)
+        ));
+        assert_eq!(
+            Micropayment::get_channel((alice(), bob())).state,
+            ChannelState::Settled
+        );
+        let sig = sign_voucher(&alice_pair(), &bob(), 0, 2, 0);
+        assert_noop!(
+            Micropayment::claim_payment(Origin::signed(bob()), alice(), 2, 0, NO_EXPIRY, sr(sig)),
+            Error::<Test>::ChannelSettled
+        );
+        assert_noop!(
+            Micropayment::initiate_close(Origin::signed(alice()), bob()),
+            Error::<Test>::ChannelNotOpen
+        );
+        // no challenge period for a settled channel
+        assert_ok!(Micropayment::finalize_close(Origin::signed(alice()), bob()));
+        assert!(!Channel::<Test>::contains_key((alice(), bob())));
+    });
+}
+
+#[test]
+fn receiver_quota_caps_total_claims() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            bob(),
+            300,
+            60
+        ));
+        let sig = sign_voucher(&alice_pair(), &bob(), 0, 1, 100);
+        assert_ok!(Micropayment::claim_payment(
+            Origin::signed(bob()),
+            alice(),
+            1,
+            100,
+            NO_EXPIRY,
+            sr(sig)
+        ));
+        assert_noop!(
+            Micropayment::set_receiver_quota(Origin::signed(alice()), bob(), Some(99)),
+            Error::<Test>::QuotaBelowClaimed
+        );
+        assert_ok!(Micropayment::set_receiver_quota(
+            Origin::signed(alice()),
+            bob(),
+            Some(150)
+        ));
+        assert_eq!(
+            micropayment_events().last(),
+            Some(&RawEvent::ReceiverQuotaSet(alice(), bob(), Some(150)))
+        );
+
+        let sig = sign_voucher(&alice_pair(), &bob(), 0, 2, 51);
+        assert_noop!(
+            Micropayment::claim_payment(Origin::signed(bob()), alice(), 2, 51, NO_EXPIRY, sr(sig)),
+            Error::<Test>::QuotaExceeded
+        );
+        let sig = sign_voucher(&alice_pair(), &bob(), 0, 2, 50);
+        assert_ok!(Micropayment::claim_payment(
+            Origin::signed(bob()),
+            alice(),
+            2,
+            50,
+            NO_EXPIRY,
+            sr(sig)
+        ));
+        assert_eq!(Micropayment::get_channel((alice(), bob())).claimed, 150);
+
+        // lifting the quota makes the rest of the deposit claimable again
+        assert_ok!(Micropayment::set_receiver_quota(
+            Origin::signed(alice()),
+            bob(),
+            None
+        ));
+        let sig = sign_voucher(&alice_pair(), &bob(), 0, 3, 150);
+        assert_ok!(Micropayment::claim_payment(
+            Origin::signed(bob()),
+            alice(),
+            3,
+            150,
+            NO_EXPIRY,
+            sr(sig)
+        ));
+        assert_eq!(Micropayment::get_channel((alice(), bob())).claimed, 300);
+    });
+}
+
+#[test]
+fn receiver_quota_is_fixed_while_closing() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            bob(),
+            300,
+            60
+        ));
+        assert_ok!(Micropayment::initiate_close(Origin::signed(alice()), bob()));
+        assert_noop!(
+            Micropayment::set_receiver_quota(Origin::signed(alice()), bob(), Some(0)),
+            Error::<Test>::ChannelNotOpen
+        );
+        // bob claims in the challenge period
+        let sig = sign_voucher(&alice_pair(), &bob(), 0, 1, 100);
+        assert_ok!(Micropayment::claim_payment(
+            Origin::signed(bob()),
+            alice(),
+            1,
+            100,
+            NO_EXPIRY,
+            sr(sig)
+        ));
+        assert_eq!(Balances::free_balance(bob()), 1_100);
+    });
+}
+
+#[test]
+fn receiver_quota_caps_stream() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(Micropayment::open_stream_channel(
+            Origin::signed(alice()),
+            bob(),
+            300,
+            60,
+            10
+        ));
+        assert_ok!(Micropayment::set_receiver_quota(
+            Origin::signed(alice()),
+            bob(),
+            Some(70)
+        ));
+        System::set_block_number(20);
+        assert_ok!(Micropayment::claim_stream(Origin::signed(bob()), alice()));
+        assert_eq!(Micropayment::get_channel((alice(), bob())).claimed, 70);
+        System::set_block_number(21);
+        assert_noop!(
+            Micropayment::claim_stream(Origin::signed(bob()), alice()),
+            Error::<Test>::NothingToClaim
+        );
+    });
+}
+
+#[test]
+fn claim_log_keeps_latest_claims_in_order() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(Micropayment::open_stream_channel(
+            Origin::signed(alice()),
+            bob(),
+            300,
+            60,
+            10
+        ));
+        for (block, session_id, amount) in &[(2, 1, 10), (3, 2, 20), (4, 3, 30)] {
+            System::set_block_number(*block);
+            let sig = sign_voucher(&alice_pair(), &bob(), 0, *session_id, *amount);
+            assert_ok!(Micropayment::claim_payment(
+                Origin::signed(bob()),
+                alice(),
+                *session_id,
+                *amount,
+                NO_EXPIRY,
+                sr(sig)
+            ));
+        }
+        assert_eq!(
+            Micropayment::claim_log(&alice(), &bob(), 0, 10),
+            vec![
+                (0, (Some(1), 10, 2)),
+                (1, (Some(2), 20, 3)),
+                (2, (Some(3), 30, 4)),
+            ]
+        );
+
+        // the stream claim is the fourth, the first one is dropped
+        System::set_block_number(5);
+        assert_ok!(Micropayment::claim_stream(Origin::signed(bob()), alice()));
+        assert_eq!(
+            Micropayment::claim_log(&alice(), &bob(), 0, 10),
+            vec![
+                (1, (Some(2), 20, 3)),
+                (2, (Some(3), 30, 4)),
+                (3, (None, 40, 5)),
+            ]
+        );
+        assert_eq!(Micropayment::claim_log_entry((alice(), bob()), 0), None);
+        assert_eq!(
+            Micropayment::claim_log(&alice(), &bob(), 2, 1),
+            vec![(2, (Some(3), 30, 4))]
+        );
+
+        assert_ok!(Micropayment::close_channel(
+            Origin::signed(bob()),
+            alice(),
+            false
+        ));
+        assert!(Micropayment::claim_log(&alice(), &bob(), 0, 10).is_empty());
+        assert_eq!(Micropayment::claim_log_range((alice(), bob())), (0, 0));
+    });
+}
+
+#[test]
+fn receiver_claims_are_rate_limited_across_channels() {
+    new_test_ext().execute_with(|| {
+        set_claim_rate(2, 10);
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            bob(),
+            300,
+            60
+        ));
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(charlie()),
+            bob(),
+            300,
+            60
+        ));
+        let claim = |pair: &sr25519::Pair, session_id: u32| {
+            let sig = sign_voucher(pair, &bob(), 0, session_id, 10);
+            Micropayment::claim_payment(
+                Origin::signed(bob()),
+                account_of(pair),
+                session_id,
+                10,
+                NO_EXPIRY,
+                sr(sig),
+            )
+        };
+        assert_ok!(claim(&alice_pair(), 1));
+        System::set_block_number(5);
+        assert_ok!(claim(&charlie_pair(), 1));
+        // the limit counts claims on every channel of the receiver
+        assert_noop!(claim(&alice_pair(), 2), Error::<Test>::RateLimited);
+        assert_noop!(claim(&charlie_pair(), 2), Error::<Test>::RateLimited);
+
+        // the window opened in block 1 runs out at block 11
+        System::set_block_number(10);
+        assert_noop!(claim(&alice_pair(), 2), Error::<Test>::RateLimited);
+        System::set_block_number(11);
+        assert_ok!(claim(&alice_pair(), 2));
+        assert_ok!(claim(&charlie_pair(), 2));
+        assert_noop!(claim(&alice_pair(), 3), Error::<Test>::RateLimited);
+        assert_eq!(Micropayment::claim_window(bob()), (11, 2));
+        assert_eq!(Balances::free_balance(bob()), 1_040);
+    });
+}
+
+#[test]
+fn unsigned_vouchers_need_require_signature_off() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            bob(),
+            300,
+            60
+        ));
+        let unsigned = || sr(vec![0u8; 64]);
+        assert_noop!(
+            Micropayment::claim_payment(
+                Origin::signed(bob()),
+                alice(),
+                1,
+                100,
+                NO_EXPIRY,
+                unsigned()
+            ),
+            "Fail to verify signature"
+        );
+
+        set_require_signature(false);
+        assert_ok!(Micropayment::claim_payment(
+            Origin::signed(bob()),
+            alice(),
+            1,
+            100,
+            NO_EXPIRY,
+            unsigned()
+        ));
+        assert_eq!(Balances::free_balance(bob()), 1_100);
+        // the other voucher checks still apply
+        assert_noop!(
+            Micropayment::claim_payment(
+                Origin::signed(bob()),
+                alice(),
+                1,
+                100,
+                NO_EXPIRY,
+                unsigned()
+            ),
+            "SessionID already consumed"
+        );
+        assert_noop!(
+            Micropayment::claim_payment(
+                Origin::signed(bob()),
+                alice(),
+                2,
+                201,
+                NO_EXPIRY,
+                unsigned()
+            ),
+            "Not enough deposit left in channel"
+        );
+        System::set_block_number(4);
+        assert_noop!(
+            Micropayment::claim_payment(Origin::signed(bob()), alice(), 2, 100, 3, unsigned()),
+            Error::<Test>::VoucherExpired
+        );
+
+        // claim_best and claim_payment_with_pubkey skip the signature as well
+        let info = Micropayment::claim_best(
+            Origin::signed(bob()),
+            alice(),
+            3,
+            vec![(50, NO_EXPIRY, unsigned()), (60, 3, unsigned())],
+        )
+        .unwrap();
+        assert_eq!(info.actual_weight, Some(2 * CLAIM_BEST_VOUCHER_WEIGHT));
+        Balances::make_free_balance_be(&eve(), 1_000);
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(eve()),
+            bob(),
+            300,
+            60
+        ));
+        assert_ok!(Micropayment::claim_payment_with_pubkey(
+            Origin::signed(bob()),
+            eve(),
+            ecdsa_pubkey(&eve_pair()),
+            1,
+            20,
+            NO_EXPIRY,
+            TypedSignature::Ecdsa(ecdsa::Signature::from_raw([0u8; 65]))
+        ));
+        assert_eq!(Balances::free_balance(bob()), 1_170);
+        // verify_signature itself still only accepts real signatures
+        assert!(
+            Micropayment::verify_signature(&alice(), &bob(), 0, 5, 10, NO_EXPIRY, &[0u8; 64])
+                .is_err()
+        );
+    });
+}
+
+#[test]
+fn config_constants_are_in_metadata() {
+    use frame_support::metadata::{DecodeDifferent, DefaultByte};
+
+    let constant = |name: &str| -> Vec<u8> {
+        Micropayment::module_constants_metadata()
+            .iter()
+            .find(|c| match c.name {
+                DecodeDifferent::Encode(n) => n == name,
+                _ => false,
+            })
+            .map(|c| match &c.value {
+                DecodeDifferent::Encode(getter) => getter.0.default_byte(),
+                _ => unreachable!(),
+            })
+            .unwrap()
+    };
+    assert_eq!(constant("MaxSignatureLen"), 64u32.encode());
+    assert_eq!(constant("MinClaimAmount"), 10u64.encode());
+    assert_eq!(constant("MaxCloseBatch"), 2u32.encode());
+    assert_eq!(constant("ChallengePeriod"), 10_000u64.encode());
+    assert_eq!(constant("ClaimGracePeriod"), 0u64.encode());
+    assert_eq!(constant("MaxClaimLog"), 3u32.encode());
+    assert_eq!(constant("RequireSignature"), true.encode());
+    assert_eq!(constant("SettlementWindow"), 5u64.encode());
+}
+
+#[test]
+fn hybrid_channel_mixes_stream_and_vouchers() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(Micropayment::open_hybrid_channel(
+            Origin::signed(alice()),
+            bob(),
+            200,
+            60,
+            10,
+            80
+        ));
+        System::set_block_number(6);
+        assert_ok!(Micropayment::claim_stream(Origin::signed(bob()), alice()));
+        assert_eq!(Micropayment::get_channel((alice(), bob())).claimed, 50);
+
+        let sig = sign_voucher(&alice_pair(), &bob(), 0, 1, 81);
+        assert_noop!(
+            Micropayment::claim_payment(Origin::signed(bob()), alice(), 1, 81, NO_EXPIRY, sr(sig)),
+            Error::<Test>::ClaimAboveMax
+        );
+        let sig = sign_voucher(&alice_pair(), &bob(), 0, 1, 80);
+        assert_ok!(Micropayment::claim_payment(
+            Origin::signed(bob()),
+            alice(),
+            1,
+            80,
+            NO_EXPIRY,
+            sr(sig)
+        ));
+        assert_eq!(Micropayment::get_channel((alice(), bob())).claimed, 130);
+
+        // the stream only pays what the voucher left of the deposit
+        System::set_block_number(50);
+        assert_ok!(Micropayment::claim_stream(Origin::signed(bob()), alice()));
+        assert_eq!(Micropayment::get_channel((alice(), bob())).claimed, 200);
+        assert_eq!(Balances::free_balance(bob()), 1_200);
+        assert_eq!(Balances::reserved_balance(alice()), 0);
+    });
+}
+
+#[test]
+fn deposit_above_free_balance_is_rejected() {
+    new_test_ext().execute_with(|| {
+        assert_noop!(
+            Micropayment::open_channel(Origin::signed(alice()), bob(), 1_001, 60),
+            Error::<Test>::InsufficientBalance
+        );
+        assert_noop!(
+            Micropayment::open_channel(Origin::signed(dave()), bob(), 1, 60),
+            Error::<Test>::InsufficientBalance
+        );
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            bob(),
+            1_000,
+            60
+        ));
+        assert_eq!(Balances::reserved_balance(alice()), 1_000);
+    });
+}
+
+// counterparty approval of a net settle of (session_id, amount) vouchers on nonce 0 channels
+fn sign_net_settle(
+    pair: &sr25519::Pair,
+    caller: &AccountId32,
+    counterparty: &AccountId32,
+    to_counterparty: (u32, u64),
+    from_counterparty: (u32, u64),
+) -> TypedSignature {
+    let msg = Micropayment::construct_net_settle_hash(
+        caller,
+        counterparty,
+        (0, to_counterparty.0, to_counterparty.1),
+        (0, from_counterparty.0, from_counterparty.1),
+    );
+    sr(pair.sign(&msg).0.to_vec())
+}
+
+#[test]
+fn net_settle_transfers_only_the_difference() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            bob(),
+            300,
+            60
+        ));
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(bob()),
+            alice(),
+            300,
+            60
+        ));
+        System::reset_events();
+        let to_bob = (
+            1,
+            120,
+            NO_EXPIRY,
+            sr(sign_voucher(&alice_pair(), &bob(), 0, 1, 120)),
+        );
+        let from_bob = (
+            4,
+            50,
+            NO_EXPIRY,
+            sr(sign_voucher(&bob_pair(), &alice(), 0, 4, 50)),
+        );
+        let approval = sign_net_settle(&bob_pair(), &alice(), &bob(), (1, 120), (4, 50));
+        assert_ok!(Micropayment::net_settle(
+            Origin::signed(alice()),
+            bob(),
+            to_bob,
+            from_bob,
+            approval
+        ));
+
+        assert_eq!(Balances::total_balance(&alice()), 930);
+        assert_eq!(Balances::total_balance(&bob()), 1_070);
+        assert_eq!(Balances::reserved_balance(alice()), 180);
+        assert_eq!(Balances::reserved_balance(bob()), 250);
+        assert_eq!(Micropayment::get_channel((alice(), bob())).claimed, 120);
+        assert_eq!(Micropayment::get_channel((bob(), alice())).claimed, 50);
+        assert!(Micropayment::get_session_id((alice(), bob()), 1));
+        assert!(Micropayment::get_session_id((bob(), alice()), 4));
+        assert_eq!(Micropayment::total_locked(), 430);
+        assert_eq!(
+            micropayment_events().last(),
+            Some(&RawEvent::NetSettled(alice(), bob(), 70))
+        );
+        // one transfer between the two accounts
+        let transfers = System::events()
+            .into_iter()
+            .filter(|r| {
+                matches!(
+                    r.event,
+                    TestEvent::pallet_balances(pallet_balances::RawEvent::ReserveRepatriated(..))
+                )
+            })
+            .count();
+        assert_eq!(transfers, 1);
+    });
+}
+
+#[test]
+fn net_settle_checks_both_vouchers() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            bob(),
+            300,
+            60
+        ));
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(bob()),
+            alice(),
+            300,
+            60
+        ));
+        let to_bob = (
+            1,
+            120,
+            NO_EXPIRY,
+            sr(sign_voucher(&alice_pair(), &bob(), 0, 1, 120)),
+        );
+        // signed by alice instead of bob
+        let forged = (
+            1,
+            200,
+            NO_EXPIRY,
+            sr(sign_voucher(&alice_pair(), &alice(), 0, 1, 200)),
+        );
+        let approval = sign_net_settle(&bob_pair(), &alice(), &bob(), (1, 120), (1, 200));
+        assert_noop!(
+            Micropayment::net_settle(Origin::signed(alice()), bob(), to_bob, forged, approval),
+            "Fail to verify signature"
+        );
+    });
+}
+
+#[test]
+fn net_settle_needs_counterparty_approval() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            bob(),
+            300,
+            60
+        ));
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(bob()),
+            alice(),
+            300,
+            60
+        ));
+        // bob holds a voucher of 200 for session 1 he has not claimed yet
+        let owed = sign_voucher(&alice_pair(), &bob(), 0, 1, 200);
+        // alice settles session 1 for the minimum with a voucher of her own
+        let to_bob = (
+            1,
+            10,
+            NO_EXPIRY,
+            sr(sign_voucher(&alice_pair(), &bob(), 0, 1, 10)),
+        );
+        let from_bob = (
+            4,
+            50,
+            NO_EXPIRY,
+            sr(sign_voucher(&bob_pair(), &alice(), 0, 4, 50)),
+        );
+        let self_approved = sign_net_settle(&alice_pair(), &alice(), &bob(), (1, 10), (4, 50));
+        assert_noop!(
+            Micropayment::net_settle(
+                Origin::signed(alice()),
+                bob(),
+                to_bob.clone(),
+                from_bob.clone(),
+                self_approved
+            ),
+            "Fail to verify signature"
+        );
+        // bob's approval of other amounts does not cover the under-settlement either
+        let approval = sign_net_settle(&bob_pair(), &alice(), &bob(), (1, 200), (4, 50));
+        assert_noop!(
+            Micropayment::net_settle(Origin::signed(alice()), bob(), to_bob, from_bob, approval),
+            "Fail to verify signature"
+        );
+        assert_ok!(Micropayment::claim_payment(
+            Origin::signed(bob()),
+            alice(),
+            1,
+            200,
+            NO_EXPIRY,
+            sr(owed)
+        ));
+        assert_eq!(Balances::free_balance(bob()), 900);
+    });
+}
+
+#[test]
+fn on_claim_hook_sees_every_payment() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            bob(),
+            300,
+            60
+        ));
+        assert_ok!(Micropayment::open_stream_channel(
+            Origin::signed(charlie()),
+            bob(),
+            300,
+            60,
+            10
+        ));
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            charlie(),
+            300,
+            60
+        ));
+        for (session_id, amount) in &[(1, 40), (2, 60)] {
+            let sig = sign_voucher(&alice_pair(), &bob(), 0, *session_id, *amount);
+            assert_ok!(Micropayment::claim_payment(
+                Origin::signed(bob()),
+                alice(),
+                *session_id,
+                *amount,
+                NO_EXPIRY,
+                sr(sig)
+            ));
+        }
+        System::set_block_number(3);
+        assert_ok!(Micropayment::claim_stream(Origin::signed(bob()), charlie()));
+        let sig = sign_voucher(&alice_pair(), &charlie(), 0, 1, 25);
+        assert_ok!(Micropayment::claim_payment(
+            Origin::signed(charlie()),
+            alice(),
+            1,
+            25,
+            NO_EXPIRY,
+            sr(sig)
+        ));
+        // a failed claim is not reported
+        let sig = sign_voucher(&alice_pair(), &bob(), 0, 3, 500);
+        assert!(Micropayment::claim_payment(
+            Origin::signed(bob()),
+            alice(),
+            3,
+            500,
+            NO_EXPIRY,
+            sr(sig)
+        )
+        .is_err());
+
+        assert_eq!(settled_to(&bob()), 120);
+        assert_eq!(settled_to(&charlie()), 25);
+        assert_eq!(settled_to(&alice()), 0);
+    });
+}
+
+#[test]
+fn voucher_amount_is_converted_before_payment() {
+    new_test_ext().execute_with(|| {
+        set_rate(2);
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            bob(),
+            300,
+            60
+        ));
+        let sig = sign_voucher(&alice_pair(), &bob(), 0, 1, 100);
+        assert_ok!(Micropayment::claim_payment(
+            Origin::signed(bob()),
+            alice(),
+            1,
+            100,
+            NO_EXPIRY,
+            sr(sig)
+        ));
+        // 200 left the deposit, the event still shows the 100 alice signed for
+        assert_eq!(Balances::free_balance(bob()), 1_200);
+        assert_eq!(Balances::reserved_balance(alice()), 100);
+        assert_eq!(Channel::<Test>::get((alice(), bob())).claimed, 200);
+        assert_eq!(settled_to(&bob()), 200);
+        assert!(micropayment_events().contains(&RawEvent::ClaimPayment(alice(), bob(), 100, 1)));
+
+        // the deposit bound applies to the converted amount
+        let sig = sign_voucher(&alice_pair(), &bob(), 0, 2, 60);
+        assert_noop!(
+            Micropayment::claim_payment(Origin::signed(bob()), alice(), 2, 60, NO_EXPIRY, sr(sig)),
+            "Not enough deposit left in channel"
+        );
+    });
+}
+
+#[test]
+fn reconcile_channel_fixes_unbacked_deposit() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            bob(),
+            300,
+            60
+        ));
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            charlie(),
+            100,
+            60
+        ));
+        let sig = sign_voucher(&alice_pair(), &bob(), 0, 1, 50);
+        assert_ok!(Micropayment::claim_payment(
+            Origin::signed(bob()),
+            alice(),
+            1,
+            50,
+            NO_EXPIRY,
+            sr(sig)
+        ));
+        // something outside the pallet released part of the escrow
+        Balances::unreserve(&alice(), 120);
+        assert_eq!(Balances::reserved_balance(alice()), 230);
+
+        assert_noop!(
+            Micropayment::reconcile_channel(Origin::signed(alice()), alice(), bob()),
+            DispatchError::BadOrigin
+        );
+        assert_ok!(Micropayment::reconcile_channel(
+            Origin::root(),
+            alice(),
+            bob()
+        ));
+        assert_eq!(Micropayment::get_channel((alice(), bob())).deposit, 180);
+        assert_eq!(
+            micropayment_events().last(),
+            Some(&RawEvent::ChannelReconciled(alice(), bob(), 300, 180))
+        );
+        assert_eq!(
+            Micropayment::total_locked(),
+            Balances::reserved_balance(alice())
+        );
+
+        // consistent now, a second run changes nothing
+        assert_ok!(Micropayment::reconcile_channel(
+            Origin::root(),
+            alice(),
+            bob()
+        ));
+        assert_eq!(Micropayment::get_channel((alice(), bob())).deposit, 180);
+
+        // closing refunds exactly what is still reserved
+        assert_ok!(Micropayment::close_channel(
+            Origin::signed(bob()),
+            alice(),
+            false
+        ));
+        assert_ok!(Micropayment::close_channel(
+            Origin::signed(charlie()),
+            alice(),
+            false
+        ));
+        assert_eq!(Balances::reserved_balance(alice()), 0);
+        assert_eq!(Micropayment::total_locked(), 0);
+    });
+}
+
+#[test]
+fn claim_above_reserved_balance_is_rejected() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            bob(),
+            300,
+            60
+        ));
+        // something outside the pallet released part of the escrow
+        Balances::unreserve(&alice(), 250);
+        let sig = sign_voucher(&alice_pair(), &bob(), 0, 1, 100);
+        assert_noop!(
+            Micropayment::claim_payment(Origin::signed(bob()), alice(), 1, 100, NO_EXPIRY, sr(sig)),
+            Error::<Test>::InsufficientReserve
+        );
+        assert_eq!(Micropayment::get_channel((alice(), bob())).claimed, 0);
+    });
+}
+
+#[test]
+fn recount_channels_repairs_receiver_count() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            bob(),
+            300,
+            60
+        ));
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            charlie(),
+            100,
+            60
+        ));
+        ReceiverCount::<Test>::insert(alice(), 7);
+
+        assert_noop!(
+            Micropayment::recount_channels(Origin::signed(alice()), alice()),
+            DispatchError::BadOrigin
+        );
+        assert_ok!(Micropayment::recount_channels(Origin::root(), alice()));
+        assert_eq!(Micropayment::receiver_count(alice()), 2);
+        assert_eq!(
+            micropayment_events().last(),
+            Some(&RawEvent::ChannelsRecounted(alice(), 7, 2))
+        );
+
+        // a sender without channels loses its stale entry
+        ReceiverCount::<Test>::insert(bob(), 1);
+        assert_ok!(Micropayment::recount_channels(Origin::root(), bob()));
+        assert!(!ReceiverCount::<Test>::contains_key(bob()));
+    });
+}
+
+fn expiring_soon_events() -> Vec<Event<Test>> {
+    micropayment_events()
+        .into_iter()
+        .filter(|e| matches!(e, RawEvent::ChannelExpiringSoon(..)))
+        .collect()
+}
+
+#[test]
+fn expiry_warning_fires_once_in_window() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            bob(),
+            300,
+            60
+        ));
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(charlie()),
+            bob(),
+            300,
+            120
+        ));
+        Timestamp::set_timestamp(49_999);
+        Micropayment::on_initialize(2);
+        assert!(expiring_soon_events().is_empty());
+
+        Timestamp::set_timestamp(50_000);
+        Micropayment::on_initialize(3);
+        assert_eq!(
+            expiring_soon_events(),
+            vec![RawEvent::ChannelExpiringSoon(alice(), bob(), 60_000)]
+        );
+        Micropayment::on_initialize(4);
+        assert_eq!(expiring_soon_events().len(), 1);
+
+        // an extended channel is announced again before its new expiration
+        assert_ok!(Micropayment::extend_channel(
+            Origin::signed(alice()),
+            bob(),
+            30_000
+        ));
+        Timestamp::set_timestamp(80_000);
+        Micropayment::on_initialize(5);
+        assert_eq!(
+            expiring_soon_events().last(),
+            Some(&RawEvent::ChannelExpiringSoon(alice(), bob(), 90_000))
+        );
+        assert_eq!(expiring_soon_events().len(), 2);
+    });
+}
+
+#[test]
+fn expiry_warnings_are_bounded_per_block() {
+    new_test_ext().execute_with(|| {
+        for receiver in &[bob(), charlie(), dave()] {
+            assert_ok!(Micropayment::open_channel(
+                Origin::signed(alice()),
+                receiver.clone(),
+                100,
+                60
+            ));
+        }
+        // closed channels are not announced
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(bob()),
+            charlie(),
+            100,
+            60
+        ));
+        assert_ok!(Micropayment::close_channel(
+            Origin::signed(charlie()),
+            bob(),
+            false
+        ));
+
+        Timestamp::set_timestamp(55_000);
+        // MaxExpiryWarnings is 2 in the mock
+        Micropayment::on_initialize(2);
+        assert_eq!(expiring_soon_events().len(), 2);
+        Micropayment::on_initialize(3);
+        assert_eq!(expiring_soon_events().len(), 3);
+        Micropayment::on_initialize(4);
+        assert_eq!(expiring_soon_events().len(), 3);
+        assert!(ExpiryBuckets::<Test>::iter_prefix(1).next().is_none());
+        assert_eq!(Micropayment::expiry_cursor(), 1);
+    });
+}
+
+#[test]
+fn undue_channels_do_not_use_up_expiry_warnings() {
+    new_test_ext().execute_with(|| {
+        // all in bucket 1, MaxExpiryWarnings is 2 in the mock
+        for (receiver, duration) in &[(bob(), 119), (charlie(), 119), (dave(), 61)] {
+            assert_ok!(Micropayment::open_channel(
+                Origin::signed(alice()),
+                receiver.clone(),
+                100,
+                *duration
+            ));
+        }
+        Timestamp::set_timestamp(51_000);
+        Micropayment::on_initialize(2);
+        assert_eq!(
+            expiring_soon_events(),
+            vec![RawEvent::ChannelExpiringSoon(alice(), dave(), 61_000)]
+        );
+        assert_eq!(Micropayment::expiry_bucket(1, (alice(), bob())), 119_000);
+    });
+}
+
+#[test]
+fn expiry_behind_cursor_is_warned_when_queued() {
+    new_test_ext().execute_with(|| {
+        set_warning_window(150_000);
+        Micropayment::on_initialize(1);
+        assert_eq!(Micropayment::expiry_cursor(), 150_000 / EXPIRY_BUCKET);
+
+        // expires in bucket 1, which the scan has already passed
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            bob(),
+            300,
+            60
+        ));
+        assert_eq!(
+            expiring_soon_events(),
+            vec![RawEvent::ChannelExpiringSoon(alice(), bob(), 60_000)]
+        );
+        assert_eq!(Micropayment::expiry_bucket(1, (alice(), bob())), 0);
+
+        // an extension that stays within the window is announced again at once
+        assert_ok!(Micropayment::extend_channel(
+            Origin::signed(alice()),
+            bob(),
+            60_000
+        ));
+        assert_eq!(
+            expiring_soon_events().last(),
+            Some(&RawEvent::ChannelExpiringSoon(alice(), bob(), 120_000))
+        );
+
+        // a channel expiring past the window is left to on_initialize
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            charlie(),
+            300,
+            600
+        ));
+        assert_eq!(expiring_soon_events().len(), 2);
+        Timestamp::set_timestamp(450_000);
+        Micropayment::on_initialize(2);
+        assert_eq!(
+            expiring_soon_events().last(),
+            Some(&RawEvent::ChannelExpiringSoon(alice(), charlie(), 600_000))
+        );
+        assert_eq!(expiring_soon_events().len(), 3);
+    });
+}
+
+#[test]
+fn expiry_scan_starts_at_current_bucket() {
+    new_test_ext().execute_with(|| {
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            bob(),
+            300,
+            60
+        ));
+        assert_ok!(Micropayment::open_channel(
+            Origin::signed(alice()),
+            charlie(),
+            300,
+            600
+        ));
+        // no block for a while, the channel to bob expired unannounced
+        Timestamp::set_timestamp(200_000);
+        Micropayment::on_initialize(2);
+        assert!(expiring_soon_events().is_empty());
+        assert_eq!(Micropayment::expiry_cursor(), 200_000 / EXPIRY_BUCKET);
+        // buckets are not read past the warning window
+        assert_eq!(
+            Micropayment::expiry_bucket(600_000 / EXPIRY_BUCKET, (alice(), charlie())),
+            600_000
+        );
+
+        // the stale entry goes with the channel
+        assert_ok!(Micropayment::close_channel(
+            Origin::signed(bob()),
+            alice(),
+            false
+        ));
+        assert_eq!(
+            Micropayment::expiry_bucket(60_000 / EXPIRY_BUCKET, (alice(), bob())),
+            0
+        );
+    });
+}
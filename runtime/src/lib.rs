@@ -24,6 +24,7 @@ use pallet_session::historical as pallet_session_historical;
 pub use pallet_staking::StakerStatus;
 use sp_runtime::{
     create_runtime_str, generic, impl_opaque_keys, ApplyExtrinsicResult, MultiSignature,
+    MultiSigner,
 };
 use sp_std::prelude::*;
 #[cfg(feature = "std")]
@@ -121,14 +122,17 @@ parameter_types! {
         pub const DisabledValidatorsThreshold: Perbill = Perbill::from_percent(17);
 }
 
+// version 2 changed the layout of the micropayment Channel storage and the arguments of its
+// calls without a storage migration: a live chain on version 1 can not be upgraded in place and
+// has to be restarted from a new genesis
 pub const VERSION: RuntimeVersion = RuntimeVersion {
     spec_name: create_runtime_str!("e2-chain"),
     impl_name: create_runtime_str!("e2-chain"),
     authoring_version: 1,
-    spec_version: 1,
+    spec_version: 2,
     impl_version: 1,
     apis: RUNTIME_API_VERSIONS,
-    transaction_version: 1,
+    transaction_version: 2,
 };
 
 pub const MILLISECS_PER_BLOCK: u64 = 6000;
@@ -429,10 +433,57 @@ impl deeper_node::Trait for Runtime {
     type Currency = Balances;
 }
 
+parameter_types! {
+    // sr25519 signatures are 64 bytes
+    pub const MaxSignatureLen: u32 = 64;
+    pub const MaxCloseBatch: u32 = 20;
+    // receivers get a day to claim once a sender starts closing a channel
+    pub const ChallengePeriod: u64 = 24 * 60 * 60 * 1000;
+    // channels are announced an hour before they expire
+    pub const WarningWindow: u64 = 60 * 60 * 1000;
+    pub const MaxExpiryWarnings: u32 = 50;
+    // claims can start as soon as a channel is opened
+    pub const ClaimGracePeriod: BlockNumber = 0;
+    pub const MaxClaimLog: u32 = 100;
+    // a zero window, receivers can claim as often as they like
+    pub const GlobalClaimRate: (u32, BlockNumber) = (0, 0);
+    pub const RequireSignature: bool = true;
+    // deferred closes are settled at the earliest a minute after they are asked for
+    pub const SettlementWindow: BlockNumber = MINUTES;
+}
+
+/// Maps a compressed secp256k1 public key to the account it controls.
+pub struct AccountFromEcdsa;
+
+impl Convert<[u8; 33], AccountId> for AccountFromEcdsa {
+    fn convert(pubkey: [u8; 33]) -> AccountId {
+        MultiSigner::from(sp_core::ecdsa::Public::from_raw(pubkey)).into_account()
+    }
+}
+
 impl micropayment::Trait for Runtime {
     type Event = Event;
     type Currency = Balances;
     type Timestamp = Timestamp;
+    type OpenOrigin = frame_system::EnsureSigned<AccountId>;
+    type MaxSignatureLen = MaxSignatureLen;
+    // never settle less than the existential deposit in one claim
+    type MinClaimAmount = ExistentialDeposit;
+    type AccountFromPubkey = AccountFromEcdsa;
+    type MaxCloseBatch = MaxCloseBatch;
+    type OnChannelOpened = ();
+    type SameEntityCheck = ();
+    type ChallengePeriod = ChallengePeriod;
+    type OnClaim = ();
+    type ForceOrigin = frame_system::EnsureRoot<AccountId>;
+    type WarningWindow = WarningWindow;
+    type MaxExpiryWarnings = MaxExpiryWarnings;
+    type RateProvider = ();
+    type ClaimGracePeriod = ClaimGracePeriod;
+    type MaxClaimLog = MaxClaimLog;
+    type GlobalClaimRate = GlobalClaimRate;
+    type RequireSignature = RequireSignature;
+    type SettlementWindow = SettlementWindow;
 }
 
 impl pallet_session::Trait for Runtime {
@@ -722,6 +773,32 @@ impl_runtime_apis! {
         }
     }
 
+    impl micropayment_runtime_api::MicropaymentApi<Block, AccountId, Balance, micropayment::PairSummaryOf<Runtime>, micropayment::ClaimRecordOf<Runtime>> for Runtime {
+        fn consumed_session_ids(sender: AccountId, receiver: AccountId, start: u32, count: u32) -> Vec<u32> {
+            Micropayment::consumed_session_ids(&sender, &receiver, start, count)
+        }
+
+        fn channel_epoch(sender: AccountId, receiver: AccountId) -> Option<u64> {
+            Micropayment::channel_epoch(&sender, &receiver)
+        }
+
+        fn pair_summary(sender: AccountId, receiver: AccountId) -> Option<micropayment::PairSummaryOf<Runtime>> {
+            Micropayment::pair_summary(&sender, &receiver)
+        }
+
+        fn remaining_claimable(sender: AccountId, receiver: AccountId) -> Balance {
+            Micropayment::remaining_claimable(&sender, &receiver)
+        }
+
+        fn claim_log(sender: AccountId, receiver: AccountId, start: u32, count: u32) -> Vec<(u32, micropayment::ClaimRecordOf<Runtime>)> {
+            Micropayment::claim_log(&sender, &receiver, start, count)
+        }
+
+        fn is_in_challenge_window(sender: AccountId, receiver: AccountId) -> bool {
+            Micropayment::is_in_challenge_window(&sender, &receiver)
+        }
+    }
+
     impl fg_primitives::GrandpaApi<Block> for Runtime {
         fn grandpa_authorities() -> GrandpaAuthorityList {
             Grandpa::grandpa_authorities()
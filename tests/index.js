@@ -9,13 +9,15 @@ function toHexString(byteArray) {
     }).join("");
 }
 
-// nonce:u64, session_id:u32
-function construct_byte_array(addr, nonce, session_id, amount) {
-    let arr = [];
-    nonce = nonce.toArray("be", 8);
-    session_id = session_id.toArray("be", 4);
-    amount = amount.toArray("le", 16); // amount is le encoded
-    arr.push(...addr, ...nonce, ...session_id, ...amount);
+// domain tag followed by the SCALE encoded voucher:
+// sender, receiver, nonce:u64, session_id:u32, amount:u128, valid_until:u32
+function construct_byte_array(sender, receiver, nonce, session_id, amount, valid_until) {
+    let arr = [...stringToU8a("e2-micropay-v1")];
+    nonce = nonce.toArray("le", 8);
+    session_id = session_id.toArray("le", 4);
+    amount = amount.toArray("le", 16);
+    valid_until = valid_until.toArray("le", 4);
+    arr.push(...sender, ...receiver, ...nonce, ...session_id, ...amount, ...valid_until);
     return arr;
 }
 
@@ -30,12 +32,14 @@ async function test() {
     let base = new BN("1000000000000000", 10); // base = 1e15
     let amount = new BN("99", 10);
     let amt = amount.mul(base);
-    let res = construct_byte_array(bob.publicKey, nonce, session_id, amt);
+    let valid_until = new BN("100000", 10); // block number
+    let res = construct_byte_array(alice.publicKey, bob.publicKey, nonce, session_id, amt, valid_until);
     let msg = blake2AsU8a(res);
 
     let signature = alice.sign(msg);
+    // passed to the chain as the TypedSignature { Sr25519: signature }
     let hexsig = toHexString(signature);
-    console.log(`nonce: ${nonce}, session_id: ${session_id}, amt: ${amount}, signature: ${hexsig}`);
+    console.log(`nonce: ${nonce}, session_id: ${session_id}, amt: ${amount}, valid_until: ${valid_until}, signature: { Sr25519: 0x${hexsig} }`);
 }
 
 //test();
@@ -55,9 +59,29 @@ async function test1() {
             ChannelOf: {
                 sender: "AccountId",
                 receiver: "AccountId",
+                nonce: "u64",
                 opened: "Timestamp",
+                opened_at: "BlockNumber",
                 expiration: "Timestamp",
+                deposit: "Balance",
+                claimed: "Balance",
+                state: "ChannelState",
+                quota: "Option<Balance>",
             },
+            ChannelState: {
+                _enum: {
+                    Open: "Null",
+                    Closing: { at: "Timestamp" },
+                    Settled: "Null",
+                },
+            },
+            TypedSignature: {
+                _enum: {
+                    Ecdsa: "[u8; 65]",
+                    Sr25519: "[u8; 64]",
+                },
+            },
+            NetVoucherOf: "(u32, Balance, BlockNumber, TypedSignature)",
         },
     });
 